@@ -0,0 +1,179 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Bluetooth device address, e.g. `D2:75:8A:E6:6A:FD`.
+///
+/// Parses colon-, dash- and underscore-separated forms in either case and
+/// always displays as upper case with colons, the way BlueZ reports it.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct BdAddr([u8; 6]);
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid Bluetooth address {0:?}, expected six hex bytes like AA:BB:CC:DD:EE:FF")]
+pub struct ParseBdAddrError(String);
+
+impl BdAddr {
+    pub fn new(bytes: [u8; 6]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn bytes(&self) -> [u8; 6] {
+        self.0
+    }
+
+    /// Object path segment BlueZ uses for the device, e.g. `dev_D2_75_8A_E6_6A_FD`
+    pub fn to_path_segment(&self) -> String {
+        format!("dev_{}", self.to_string().replace(':', "_"))
+    }
+
+    /// Parse a segment like `dev_D2_75_8A_E6_6A_FD`
+    pub fn from_path_segment(segment: &str) -> Option<Self> {
+        segment.strip_prefix("dev_")?.parse().ok()
+    }
+
+    /// Address of the device an object path belongs to, e.g. of
+    /// `/org/bluez/hci0/dev_D2_75_8A_E6_6A_FD/service000a`
+    pub fn from_object_path(path: &str) -> Option<Self> {
+        path.split('/').find_map(Self::from_path_segment)
+    }
+}
+
+impl FromStr for BdAddr {
+    type Err = ParseBdAddrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseBdAddrError(s.to_string());
+        let separator = s
+            .chars()
+            .find(|c| matches!(c, ':' | '-' | '_'))
+            .ok_or_else(invalid)?;
+
+        let mut bytes = [0; 6];
+        let mut parts = s.trim().split(separator);
+        for byte in &mut bytes {
+            let part = parts.next().ok_or_else(invalid)?;
+            if part.len() != 2 || !part.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(invalid());
+            }
+            *byte = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(Self(bytes))
+    }
+}
+
+impl TryFrom<String> for BdAddr {
+    type Error = ParseBdAddrError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<BdAddr> for String {
+    fn from(address: BdAddr) -> Self {
+        address.to_string()
+    }
+}
+
+impl fmt::Display for BdAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02X}:{b:02X}:{c:02X}:{d:02X}:{e:02X}:{g:02X}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: BdAddr = BdAddr([0xD2, 0x75, 0x8A, 0xE6, 0x6A, 0xFD]);
+
+    #[test]
+    fn parses_separators_and_case() {
+        for input in [
+            "D2:75:8A:E6:6A:FD",
+            "D2-75-8A-E6-6A-FD",
+            "D2_75_8A_E6_6A_FD",
+            "d2:75:8a:e6:6a:fd",
+            "d2-75-8A-e6-6a-Fd",
+            " D2:75:8A:E6:6A:FD\n",
+        ] {
+            assert_eq!(input.parse(), Ok(ADDRESS), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        for input in [
+            "",
+            "D2758AE66AFD",
+            "D2:75:8A:E6:6A",
+            "D2:75:8A:E6:6A:FD:00",
+            "D2:75:8A:E6:6A:F",
+            "D2:75:8A:E6:6A:FDD",
+            "D2:75:8A:E6:6A:",
+            "D2:75:8A-E6:6A:FD",
+            "G2:75:8A:E6:6A:FD",
+            "D2:75:8A:E6:6A:+F",
+            "D2 75 8A E6 6A FD",
+        ] {
+            assert_eq!(
+                input.parse::<BdAddr>(),
+                Err(ParseBdAddrError(input.to_string())),
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn displays_upper_case_with_colons() {
+        assert_eq!(ADDRESS.to_string(), "D2:75:8A:E6:6A:FD");
+        assert_eq!(
+            "0a:0b:0c:0d:0e:0f".parse::<BdAddr>().unwrap().to_string(),
+            "0A:0B:0C:0D:0E:0F"
+        );
+    }
+
+    #[test]
+    fn path_segment_round_trip() {
+        let segment = ADDRESS.to_path_segment();
+        assert_eq!(segment, "dev_D2_75_8A_E6_6A_FD");
+        assert_eq!(BdAddr::from_path_segment(&segment), Some(ADDRESS));
+        assert_eq!(BdAddr::from_path_segment("D2_75_8A_E6_6A_FD"), None);
+        assert_eq!(BdAddr::from_path_segment("dev_D2_75_8A"), None);
+    }
+
+    #[test]
+    fn from_object_path() {
+        for (path, expected) in [
+            ("/org/bluez/hci0/dev_D2_75_8A_E6_6A_FD", Some(ADDRESS)),
+            (
+                "/org/bluez/hci0/dev_D2_75_8A_E6_6A_FD/service000a/char000b",
+                Some(ADDRESS),
+            ),
+            ("/org/bluez/hci0", None),
+            ("/org/bluez/hci0/dev_nonsense", None),
+            ("", None),
+        ] {
+            assert_eq!(BdAddr::from_object_path(path), expected, "{path:?}");
+        }
+        let path = format!("/org/bluez/hci1/{}", ADDRESS.to_path_segment());
+        assert_eq!(BdAddr::from_object_path(&path), Some(ADDRESS));
+    }
+
+    #[test]
+    fn serde_uses_the_string_form() {
+        let json = serde_json::to_string(&ADDRESS).unwrap();
+        assert_eq!(json, "\"D2:75:8A:E6:6A:FD\"");
+        assert_eq!(serde_json::from_str::<BdAddr>(&json).unwrap(), ADDRESS);
+        assert!(serde_json::from_str::<BdAddr>("\"D2:75\"").is_err());
+    }
+}
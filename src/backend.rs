@@ -0,0 +1,458 @@
+use async_trait::async_trait;
+use futures_util::stream::{self, StreamExt};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::{
+    config::DeviceConfig, read_concurrently, sort_batteries, BackendState, BackendStateStream,
+    BatteryInfo, BatterySource, BatteryStream, BdAddr, ChargeState, Config, DeviceEvent,
+    DeviceEventStream, DeviceInfo, DeviceInformation, DeviceReading, DiscoveryStream, ReadOptions,
+    ReadOrigin, Result, ZmkBatteryReader, ZmkError,
+};
+
+/// Source of device and battery information.
+///
+/// `ZmkBatteryReader` implements this against BlueZ; `FakeBackend` is an
+/// in-memory implementation for exercising consumers without hardware.
+#[async_trait]
+pub trait BatteryBackend: Send + Sync {
+    /// List all known devices
+    async fn list_devices(&self) -> Result<Vec<DeviceInfo>>;
+
+    /// List only HID devices with battery reporting
+    async fn list_zmk_devices(&self) -> Result<Vec<DeviceInfo>> {
+        let mut devices = self.list_devices().await?;
+        devices.retain(DeviceInfo::is_zmk_candidate);
+        Ok(devices)
+    }
+
+    /// Read all battery levels reported by a device
+    async fn read_battery_levels(
+        &self,
+        device_address: BdAddr,
+        options: &ReadOptions,
+    ) -> Result<Vec<BatteryInfo>>;
+
+    /// Read every enabled device of `config` concurrently
+    async fn read_all(&self, config: &Config) -> Vec<DeviceReading> {
+        let known = if config
+            .get_enabled_devices()
+            .iter()
+            .all(|d| d.address.is_some())
+        {
+            Ok(Vec::new())
+        } else {
+            self.list_devices().await
+        };
+        read_concurrently(config, &known, |address, options| async move {
+            self.read_battery_levels(address, &options).await
+        })
+        .await
+    }
+
+    /// Address of the device `device` refers to, looking it up by name or
+    /// alias if it has no address
+    async fn resolve_address(&self, device: &DeviceConfig) -> Result<BdAddr> {
+        match device.address {
+            Some(address) => Ok(address),
+            None => device.resolve_address(&self.list_devices().await?),
+        }
+    }
+
+    /// Subscribe to battery level updates pushed by a device
+    async fn subscribe_battery_levels(
+        &self,
+        device_address: BdAddr,
+        options: &ReadOptions,
+    ) -> Result<BatteryStream>;
+
+    /// Read the Device Information Service of a device
+    async fn read_device_info(
+        &self,
+        device_address: BdAddr,
+        options: &ReadOptions,
+    ) -> Result<DeviceInformation>;
+
+    /// Watch devices for connection state changes
+    async fn device_events(&self) -> Result<DeviceEventStream>;
+
+    /// Watch the backend coming and going, starting with its current state
+    async fn backend_states(&self) -> Result<BackendStateStream>;
+
+    /// Scan for keyboards for at most `duration`, on one adapter or all
+    async fn discover(&self, duration: Duration, adapter: Option<&str>) -> Result<DiscoveryStream>;
+}
+
+#[async_trait]
+impl BatteryBackend for ZmkBatteryReader {
+    async fn list_devices(&self) -> Result<Vec<DeviceInfo>> {
+        ZmkBatteryReader::list_devices(self).await
+    }
+
+    async fn read_battery_levels(
+        &self,
+        device_address: BdAddr,
+        options: &ReadOptions,
+    ) -> Result<Vec<BatteryInfo>> {
+        ZmkBatteryReader::read_battery_levels(self, device_address, options).await
+    }
+
+    async fn read_all(&self, config: &Config) -> Vec<DeviceReading> {
+        ZmkBatteryReader::read_all(self, config).await
+    }
+
+    async fn subscribe_battery_levels(
+        &self,
+        device_address: BdAddr,
+        options: &ReadOptions,
+    ) -> Result<BatteryStream> {
+        ZmkBatteryReader::subscribe_battery_levels(self, device_address, options).await
+    }
+
+    async fn read_device_info(
+        &self,
+        device_address: BdAddr,
+        options: &ReadOptions,
+    ) -> Result<DeviceInformation> {
+        ZmkBatteryReader::read_device_info(self, device_address, options).await
+    }
+
+    async fn device_events(&self) -> Result<DeviceEventStream> {
+        ZmkBatteryReader::device_events(self).await
+    }
+
+    async fn backend_states(&self) -> Result<BackendStateStream> {
+        ZmkBatteryReader::backend_states(self).await
+    }
+
+    async fn discover(&self, duration: Duration, adapter: Option<&str>) -> Result<DiscoveryStream> {
+        ZmkBatteryReader::discover(self, duration, adapter).await
+    }
+}
+
+/// Scriptable in-memory backend.
+///
+/// Reads of devices without scripted levels fail like BlueZ would: with
+/// `NoBatteryService` for devices added via `with_device` and `NotPaired`
+/// for unknown addresses. Disconnected devices fail with `Disconnected`
+/// unless the read may connect them. While the backend is set unavailable,
+/// everything fails with `BluezUnavailable`.
+///
+/// Clones share their state, so a backend can still be scripted after a
+/// clone of it was handed to a `Monitor`.
+#[derive(Debug, Default, Clone)]
+pub struct FakeBackend {
+    state: Arc<Mutex<FakeState>>,
+}
+
+#[derive(Debug, Default)]
+struct FakeState {
+    devices: Vec<DeviceInfo>,
+    levels: HashMap<BdAddr, Result<Vec<BatteryInfo>>>,
+    subscribers: HashMap<BdAddr, Vec<mpsc::UnboundedSender<BatteryInfo>>>,
+    device_info: HashMap<BdAddr, DeviceInformation>,
+    event_subscribers: Vec<mpsc::UnboundedSender<DeviceEvent>>,
+    unavailable: bool,
+    /// Devices in range that `discover` finds
+    discoverable: Vec<DeviceInfo>,
+    backend_subscribers: Vec<mpsc::UnboundedSender<BackendState>>,
+}
+
+impl FakeState {
+    fn check_available(&self) -> Result<()> {
+        if self.unavailable {
+            Err(ZmkError::BluezUnavailable)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl FakeBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a connected ZMK keyboard on `hci0` to the list returned by
+    /// `list_devices`
+    pub fn with_device(self, name: &str, address: BdAddr) -> Self {
+        self.with_device_on(name, address, "hci0")
+    }
+
+    /// Add a connected ZMK keyboard on a specific adapter
+    pub fn with_device_on(self, name: &str, address: BdAddr, adapter: &str) -> Self {
+        self.with_device_info(DeviceInfo {
+            name: Some(name.to_string()),
+            alias: Some(name.to_string()),
+            address,
+            adapter: adapter.to_string(),
+            paired: true,
+            bonded: true,
+            connected: true,
+            trusted: true,
+            services_resolved: true,
+            icon: Some("input-keyboard".to_string()),
+            has_battery_service: true,
+            ..Default::default()
+        })
+    }
+
+    /// Add an arbitrary device to the list returned by `list_devices`
+    pub fn with_device_info(self, device: DeviceInfo) -> Self {
+        self.state.lock().unwrap().devices.push(device);
+        self
+    }
+
+    /// Add a device that is in range but unknown until `discover` finds it
+    pub fn with_discoverable(self, device: DeviceInfo) -> Self {
+        self.state.lock().unwrap().discoverable.push(device);
+        self
+    }
+
+    /// Set the levels returned for a device, as `(name, level)` pairs
+    pub fn set_levels(&self, address: BdAddr, levels: &[(&str, u8)]) {
+        let mut batteries = levels
+            .iter()
+            .map(|(name, level)| BatteryInfo {
+                name: name.to_string(),
+                level: *level,
+                source: BatterySource::from_description(Some(name)),
+                retries: 0,
+                origin: ReadOrigin::Device,
+                reconnected: false,
+                charging: ChargeState::Unknown,
+            })
+            .collect::<Vec<_>>();
+        sort_batteries(&mut batteries);
+        self.state
+            .lock()
+            .unwrap()
+            .levels
+            .insert(address, Ok(batteries));
+    }
+
+    /// Update one battery level and push it to subscribers of the device
+    pub fn notify_level(&self, address: BdAddr, name: &str, level: u8) {
+        let mut state = self.state.lock().unwrap();
+        let info = BatteryInfo {
+            name: name.to_string(),
+            level,
+            source: BatterySource::from_description(Some(name)),
+            retries: 0,
+            origin: ReadOrigin::Device,
+            reconnected: false,
+            charging: ChargeState::Unknown,
+        };
+
+        if let Some(Ok(batteries)) = state.levels.get_mut(&address) {
+            match batteries.iter_mut().find(|b| b.name == name) {
+                Some(battery) => battery.level = level,
+                None => {
+                    batteries.push(info.clone());
+                    sort_batteries(batteries);
+                }
+            }
+        }
+
+        if let Some(subscribers) = state.subscribers.get_mut(&address) {
+            subscribers.retain(|tx| tx.send(info.clone()).is_ok());
+        }
+    }
+
+    /// Set the Device Information Service contents of a device
+    pub fn set_device_info(&self, address: BdAddr, info: DeviceInformation) {
+        self.state.lock().unwrap().device_info.insert(address, info);
+    }
+
+    /// Push a device event to all `device_events` streams, updating the
+    /// device's connection state
+    pub fn emit_event(&self, event: DeviceEvent) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(device) = state
+            .devices
+            .iter_mut()
+            .find(|d| d.address == event.address())
+        {
+            match event {
+                DeviceEvent::Connected { .. } => device.connected = true,
+                DeviceEvent::Disconnected { .. } => {
+                    device.connected = false;
+                    device.services_resolved = false;
+                }
+                DeviceEvent::ServicesResolved { .. } => device.services_resolved = true,
+                DeviceEvent::BatteryServiceAppeared { .. } => device.has_battery_service = true,
+                DeviceEvent::Removed { .. } => {}
+            }
+        }
+        state
+            .event_subscribers
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    /// Simulate BlueZ stopping or starting again. Stopping ends all battery
+    /// level subscriptions.
+    pub fn set_backend_state(&self, backend: BackendState) {
+        let mut state = self.state.lock().unwrap();
+        state.unavailable = backend == BackendState::Unavailable;
+        if state.unavailable {
+            state.subscribers.clear();
+        }
+        state
+            .backend_subscribers
+            .retain(|tx| tx.send(backend).is_ok());
+    }
+
+    /// Make reads for a device fail with the given error
+    pub fn set_error(&self, address: BdAddr, error: ZmkError) {
+        self.state
+            .lock()
+            .unwrap()
+            .levels
+            .insert(address, Err(error));
+    }
+}
+
+#[async_trait]
+impl BatteryBackend for FakeBackend {
+    async fn list_devices(&self) -> Result<Vec<DeviceInfo>> {
+        let state = self.state.lock().unwrap();
+        state.check_available()?;
+        Ok(state.devices.clone())
+    }
+
+    async fn read_battery_levels(
+        &self,
+        device_address: BdAddr,
+        options: &ReadOptions,
+    ) -> Result<Vec<BatteryInfo>> {
+        let mut state = self.state.lock().unwrap();
+        state.check_available()?;
+
+        let mut reconnected = false;
+        if let Some(device) = state
+            .devices
+            .iter_mut()
+            .find(|d| d.address == device_address)
+        {
+            if !device.connected {
+                if options.connect_timeout.is_none() {
+                    return Err(ZmkError::Disconnected(device_address));
+                }
+                device.connected = true;
+                device.services_resolved = true;
+                reconnected = true;
+            }
+        }
+
+        match state.levels.get(&device_address) {
+            Some(Ok(batteries)) => Ok(batteries
+                .iter()
+                .cloned()
+                .map(|battery| BatteryInfo {
+                    reconnected,
+                    ..battery
+                })
+                .collect()),
+            Some(Err(error)) => Err(error.clone()),
+            None if state.devices.iter().any(|d| d.address == device_address) => {
+                Err(ZmkError::NoBatteryService(device_address))
+            }
+            None => Err(ZmkError::NotPaired(device_address)),
+        }
+    }
+
+    async fn subscribe_battery_levels(
+        &self,
+        device_address: BdAddr,
+        _options: &ReadOptions,
+    ) -> Result<BatteryStream> {
+        let mut state = self.state.lock().unwrap();
+        state.check_available()?;
+        if let Some(Err(error)) = state.levels.get(&device_address) {
+            return Err(error.clone());
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        state
+            .subscribers
+            .entry(device_address)
+            .or_default()
+            .push(tx);
+
+        Ok(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|info| (info, rx))
+        })
+        .boxed())
+    }
+
+    async fn read_device_info(
+        &self,
+        device_address: BdAddr,
+        _options: &ReadOptions,
+    ) -> Result<DeviceInformation> {
+        let state = self.state.lock().unwrap();
+        state.check_available()?;
+        if let Some(Err(error)) = state.levels.get(&device_address) {
+            return Err(error.clone());
+        }
+        Ok(state
+            .device_info
+            .get(&device_address)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn device_events(&self) -> Result<DeviceEventStream> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.state.lock().unwrap().event_subscribers.push(tx);
+
+        Ok(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|event| (event, rx))
+        })
+        .boxed())
+    }
+
+    async fn backend_states(&self) -> Result<BackendStateStream> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut state = self.state.lock().unwrap();
+        let current = if state.unavailable {
+            BackendState::Unavailable
+        } else {
+            BackendState::Available
+        };
+        let _ = tx.send(current);
+        state.backend_subscribers.push(tx);
+
+        Ok(stream::unfold(rx, |mut rx| async move {
+            rx.recv().await.map(|backend| (backend, rx))
+        })
+        .boxed())
+    }
+
+    async fn discover(
+        &self,
+        _duration: Duration,
+        adapter: Option<&str>,
+    ) -> Result<DiscoveryStream> {
+        let mut state = self.state.lock().unwrap();
+        state.check_available()?;
+
+        // Like BlueZ, remember what was found
+        let found: Vec<_> = state
+            .discoverable
+            .iter()
+            .filter(|d| adapter.is_none_or(|name| name == d.adapter))
+            .filter(|d| d.is_zmk_candidate())
+            .cloned()
+            .collect();
+        for device in &found {
+            if !state.devices.iter().any(|d| d.address == device.address) {
+                state.devices.push(device.clone());
+            }
+        }
+
+        Ok(stream::iter(found).boxed())
+    }
+}
@@ -1,13 +1,39 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use serde::Serialize;
 use std::env;
-use zmk_battery_monitor::Config;
+use std::time::Duration;
+use zmk_battery_monitor::{Config, ZmkBatteryReader};
 
-fn main() -> Result<()> {
+/// Scan duration when none is given
+const DEFAULT_SCAN_SECS: u64 = 10;
+
+/// `[[devices]]` entry printed for a keyboard found by a scan
+#[derive(Serialize)]
+struct Snippet {
+    devices: [SnippetDevice; 1],
+}
+
+#[derive(Serialize)]
+struct SnippetDevice {
+    name: String,
+    address: String,
+    enabled: bool,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
 
     if args.len() > 1 && args[1] == "generate" {
         // Generate template config
         println!("{}", Config::generate_template());
+    } else if args.len() > 1 && args[1] == "scan" {
+        let secs = match args.get(2) {
+            Some(secs) => secs.parse().context("Scan duration must be in seconds")?,
+            None => DEFAULT_SCAN_SECS,
+        };
+        scan(Duration::from_secs(secs), args.get(3).map(String::as_str)).await?;
     } else {
         // Show current config location and status
         let config_path = Config::config_path()?;
@@ -24,6 +50,10 @@ fn main() -> Result<()> {
                         config.general.update_interval
                     );
                     println!("  Log level: {}", config.general.log_level);
+                    println!(
+                        "  Read timeout: {} ms, {} retries",
+                        config.general.read_timeout_ms, config.general.max_retries
+                    );
                     println!("\nDevices:");
                     for device in &config.devices {
                         let status = if device.enabled {
@@ -31,27 +61,87 @@ fn main() -> Result<()> {
                         } else {
                             "disabled"
                         };
-                        println!("  - {} ({}) [{}]", device.name, device.address, status);
+                        println!("  - {} ({}) [{}]", device.name, device.selector(), status);
+                        if let Some(adapter) = &device.adapter {
+                            println!("    Adapter: {adapter}");
+                        }
+                        if device.auto_connect {
+                            println!("    Auto-connect: on");
+                        }
                         println!(
                             "    Low battery threshold: {}%",
                             device.low_battery_threshold
                         );
+                        for (tier, level) in &device.thresholds {
+                            println!("    Threshold {tier}: {level}%");
+                        }
+                        for (battery, thresholds) in &device.battery_thresholds {
+                            for (tier, level) in thresholds {
+                                println!("    Threshold {tier} ({battery}): {level}%");
+                            }
+                        }
                     }
                     println!("\nTray:");
                     println!("  Enabled: {}", config.tray.enabled);
                     println!("  Show percentage: {}", config.tray.show_percentage_in_tray);
                 }
                 Err(e) => {
-                    eprintln!("Error loading config: {}", e);
+                    eprintln!("Error loading config: {e:#}");
                 }
             }
         } else {
             println!("No config file found at: {}", config_path.display());
             println!("\nRun with 'generate' to create a template:");
             println!("  {} generate > config.toml", args[0]);
+            println!("\nRun with 'scan' to find nearby keyboards:");
+            println!("  {} scan [seconds] [adapter]", args[0]);
             println!("\nOr run the main program to create a default config automatically.");
         }
     }
 
     Ok(())
 }
+
+/// Scan for keyboards and print a config entry for each one found
+async fn scan(duration: Duration, adapter: Option<&str>) -> Result<()> {
+    let reader = ZmkBatteryReader::new().await?;
+    let mut found = reader.discover(duration, adapter).await?;
+
+    println!("Scanning for {} seconds...", duration.as_secs());
+    let mut devices = Vec::new();
+    while let Some(device) = found.next().await {
+        let rssi = device
+            .rssi
+            .map(|rssi| format!(", {rssi} dBm"))
+            .unwrap_or_default();
+        let paired = if device.paired { ", paired" } else { "" };
+        println!(
+            "  {} - {} [{}{rssi}{paired}]",
+            device.display_name(),
+            device.address,
+            device.adapter
+        );
+        devices.push(device);
+    }
+
+    if devices.is_empty() {
+        println!(
+            "No keyboards found. Make sure the keyboard is advertising (e.g. on a free profile)."
+        );
+        return Ok(());
+    }
+
+    println!("\nPair the keyboard, then add it to the config:");
+    for device in devices {
+        // Serialized rather than formatted, so that any name is valid TOML
+        let snippet = Snippet {
+            devices: [SnippetDevice {
+                name: device.display_name(),
+                address: device.address.to_string(),
+                enabled: true,
+            }],
+        };
+        print!("\n{}", toml::to_string(&snippet)?);
+    }
+    Ok(())
+}
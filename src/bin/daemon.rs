@@ -0,0 +1,352 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+use tokio::net::TcpListener;
+use zbus::object_server::InterfaceRef;
+use zbus::zvariant::OwnedObjectPath;
+use zbus::{fdo, interface, Connection, SignalContext};
+use zmk_battery_monitor::metrics;
+use zmk_battery_monitor::{
+    BackendState, ChargeEvent, Config, DeviceStatus, History, HookRunner, Monitor, MonitorEvent,
+    Notifier, RefreshHandle, ZmkBatteryReader,
+};
+
+/// Well-known name of the service on the session bus
+const SERVICE_NAME: &str = "org.zmk.BatteryMonitor";
+/// Root object; devices live below it as `device0`, `device1`, ...
+const ROOT_PATH: &str = "/org/zmk/BatteryMonitor";
+
+type Devices = Arc<Mutex<Vec<DeviceStatus>>>;
+
+fn device_path(index: usize) -> String {
+    format!("{ROOT_PATH}/device{index}")
+}
+
+/// Root object, `org.zmk.BatteryMonitor1`
+struct Manager {
+    device_count: usize,
+    refresh: RefreshHandle,
+}
+
+#[interface(name = "org.zmk.BatteryMonitor1")]
+impl Manager {
+    /// Read all devices now
+    async fn refresh(&self) -> fdo::Result<()> {
+        request_refresh(&self.refresh).await
+    }
+
+    /// Object paths of the configured devices, in config order
+    #[zbus(property)]
+    fn devices(&self) -> Vec<OwnedObjectPath> {
+        (0..self.device_count)
+            .filter_map(|i| OwnedObjectPath::try_from(device_path(i)).ok())
+            .collect()
+    }
+}
+
+/// One configured device, `org.zmk.BatteryMonitor.Device1`
+struct DeviceObject {
+    index: usize,
+    devices: Devices,
+    refresh: RefreshHandle,
+}
+
+impl DeviceObject {
+    fn with_status<T>(&self, f: impl FnOnce(&DeviceStatus) -> T) -> T {
+        f(&self.devices.lock().unwrap()[self.index])
+    }
+}
+
+#[interface(name = "org.zmk.BatteryMonitor.Device1")]
+impl DeviceObject {
+    /// Read all devices now
+    async fn refresh(&self) -> fdo::Result<()> {
+        request_refresh(&self.refresh).await
+    }
+
+    #[zbus(property)]
+    fn name(&self) -> String {
+        self.with_status(|status| status.device.name.clone())
+    }
+
+    /// Resolved Bluetooth address, empty while unknown
+    #[zbus(property)]
+    fn address(&self) -> String {
+        self.with_status(|status| {
+            status
+                .address
+                .map(|address| address.to_string())
+                .unwrap_or_default()
+        })
+    }
+
+    /// `(battery name, level in percent)`, Central first
+    #[zbus(property)]
+    fn levels(&self) -> Vec<(String, u8)> {
+        self.with_status(|status| {
+            status
+                .batteries
+                .iter()
+                .map(|b| (b.name.clone(), b.level))
+                .collect()
+        })
+    }
+
+    /// Battery name to "charging", "discharging", "fully charged" or "unknown"
+    #[zbus(property)]
+    fn charge_states(&self) -> HashMap<String, String> {
+        self.with_status(|status| {
+            status
+                .batteries
+                .iter()
+                .map(|b| (b.name.clone(), b.charging.to_string()))
+                .collect()
+        })
+    }
+
+    #[zbus(property)]
+    fn connected(&self) -> bool {
+        self.with_status(|status| status.connected)
+    }
+
+    /// Unix time of the last successful read or update, 0 if there was none
+    #[zbus(property)]
+    fn last_updated(&self) -> u64 {
+        self.with_status(|status| {
+            status
+                .last_updated
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0)
+        })
+    }
+
+    /// Error of the last read, empty if it succeeded
+    #[zbus(property)]
+    fn error(&self) -> String {
+        self.with_status(|status| {
+            status
+                .error
+                .as_ref()
+                .map(|e| e.to_string())
+                .unwrap_or_default()
+        })
+    }
+
+    /// A battery level changed, whether read or pushed by the keyboard
+    #[zbus(signal)]
+    async fn level_changed(ctxt: &SignalContext<'_>, battery: &str, level: u8) -> zbus::Result<()>;
+
+    /// A battery started charging at `level`
+    #[zbus(signal)]
+    async fn charging_started(
+        ctxt: &SignalContext<'_>,
+        battery: &str,
+        level: u8,
+    ) -> zbus::Result<()>;
+
+    /// A battery was charged to `level`, to 100% or until it was unplugged
+    #[zbus(signal)]
+    async fn charged(ctxt: &SignalContext<'_>, battery: &str, level: u8) -> zbus::Result<()>;
+}
+
+async fn request_refresh(refresh: &RefreshHandle) -> fdo::Result<()> {
+    if refresh.refresh().await {
+        Ok(())
+    } else {
+        Err(fdo::Error::Failed("Monitor is shutting down".to_string()))
+    }
+}
+
+/// Value of a `--name value` command line option
+fn option(name: &str) -> Option<String> {
+    let mut args = std::env::args().skip_while(|arg| arg != name);
+    args.next()?;
+    args.next()
+}
+
+/// Announce the current state of a device to clients
+async fn publish(device: &InterfaceRef<DeviceObject>) -> zbus::Result<()> {
+    let ctxt = device.signal_context();
+    let device = device.get().await;
+    device.address_changed(ctxt).await?;
+    device.levels_changed(ctxt).await?;
+    device.charge_states_changed(ctxt).await?;
+    device.connected_changed(ctxt).await?;
+    device.last_updated_changed(ctxt).await?;
+    device.error_changed(ctxt).await
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let config = Config::load()?;
+    if config.get_enabled_devices().is_empty() {
+        eprintln!("No enabled devices found in config!");
+        eprintln!(
+            "Please edit the config file at: {}",
+            Config::config_path()?.display()
+        );
+        return Ok(());
+    }
+
+    // Claim the name before the first read, which can take a while, so that
+    // a second instance fails right away
+    let conn = Connection::session().await?;
+    conn.request_name(SERVICE_NAME)
+        .await
+        .with_context(|| format!("Failed to claim {SERVICE_NAME}, is the daemon running?"))?;
+
+    let reader = ZmkBatteryReader::new()
+        .await?
+        .with_retry_policy(config.general.retry_policy());
+    let history = History::open_enabled(&config.history).unwrap_or_else(|e| {
+        eprintln!("Battery history disabled: {e:#}");
+        None
+    });
+    let mut monitor = Monitor::start_with_history(Box::new(reader), config.clone(), history).await;
+    let devices = monitor.devices();
+    // The tray may already be notifying from the same config
+    let notify =
+        config.notifications.enabled && !std::env::args().any(|arg| arg == "--no-notifications");
+    let mut notifier = if notify {
+        Notifier::new(&config)
+            .await
+            .map_err(|e| eprintln!("Desktop notifications disabled: {e}"))
+            .ok()
+    } else {
+        None
+    };
+    // Likewise for hooks
+    let mut hooks =
+        (!std::env::args().any(|arg| arg == "--no-hooks")).then(|| HookRunner::new(&config));
+    let device_count = devices.lock().unwrap().len();
+
+    // Command line options take precedence over `[metrics]`
+    let textfile_dir = option("--textfile")
+        .map(PathBuf::from)
+        .or(config.metrics.textfile_dir.clone());
+    if let Some(listen) = option("--metrics").or(config.metrics.listen.clone()) {
+        let listener = TcpListener::bind(&listen)
+            .await
+            .with_context(|| format!("Failed to listen for metrics on {listen}"))?;
+        println!("Serving metrics on http://{listen}/metrics");
+        tokio::spawn(metrics::serve(listener, Arc::clone(&devices)));
+    }
+    let write_textfile = || {
+        if let Some(dir) = &textfile_dir {
+            if let Err(e) = metrics::write_textfile(dir, &devices.lock().unwrap()) {
+                eprintln!("{e:#}");
+            }
+        }
+    };
+    write_textfile();
+
+    let refresh = monitor.refresh_handle();
+
+    let server = conn.object_server();
+    server.at(ROOT_PATH, fdo::ObjectManager).await?;
+    server
+        .at(
+            ROOT_PATH,
+            Manager {
+                device_count,
+                refresh: refresh.clone(),
+            },
+        )
+        .await?;
+    let mut objects = Vec::new();
+    for index in 0..device_count {
+        let path = device_path(index);
+        server
+            .at(
+                path.as_str(),
+                DeviceObject {
+                    index,
+                    devices: Arc::clone(&devices),
+                    refresh: refresh.clone(),
+                },
+            )
+            .await?;
+        objects.push(server.interface::<_, DeviceObject>(path).await?);
+    }
+
+    println!("Serving {SERVICE_NAME} on the session bus");
+    for device in config.get_enabled_devices() {
+        println!("Monitoring: {} ({})", device.name, device.selector());
+    }
+
+    // D-Bus clients ask the monitor to refresh through its handle, so
+    // waiting for the next change is never interrupted
+    loop {
+        let event = monitor.next().await;
+        write_textfile();
+        if let Some(hooks) = hooks.as_mut() {
+            hooks.handle(&event, &devices);
+        }
+        if let Some(notifier) = notifier.as_mut() {
+            if let Err(e) = notifier.handle(&event, &devices).await {
+                eprintln!("Failed to send notification: {e}");
+            }
+        }
+        // Clients missing a signal is no reason to stop monitoring
+        if let Err(e) = announce(&event, &objects).await {
+            eprintln!("Failed to announce change on D-Bus: {e}");
+        }
+    }
+}
+
+/// Emit the signals and property changes for `event`
+async fn announce(
+    event: &MonitorEvent,
+    objects: &[InterfaceRef<DeviceObject>],
+) -> zbus::Result<()> {
+    match event {
+        MonitorEvent::LevelChanged {
+            device, battery, ..
+        } => {
+            let object = &objects[*device];
+            DeviceObject::level_changed(object.signal_context(), &battery.name, battery.level)
+                .await?;
+            publish(object).await?;
+        }
+        MonitorEvent::Charge {
+            device,
+            battery,
+            event,
+        } => {
+            let ctxt = objects[*device].signal_context();
+            match event {
+                ChargeEvent::Started { level } => {
+                    DeviceObject::charging_started(ctxt, battery, *level).await?
+                }
+                ChargeEvent::Charged { level } => {
+                    DeviceObject::charged(ctxt, battery, *level).await?
+                }
+            }
+            publish(&objects[*device]).await?;
+        }
+        MonitorEvent::Device { device, .. } => publish(&objects[*device]).await?,
+        MonitorEvent::Backend(state) => {
+            match state {
+                BackendState::Unavailable => {
+                    eprintln!("Bluetooth service stopped, waiting for it to return");
+                }
+                BackendState::Available => println!("Bluetooth service is back"),
+            }
+            for object in objects {
+                publish(object).await?;
+            }
+        }
+        MonitorEvent::HistoryFailed(e) => {
+            eprintln!("Failed to record battery history: {e}");
+        }
+        MonitorEvent::Refreshed => {
+            for object in objects {
+                publish(object).await?;
+            }
+        }
+    }
+    Ok(())
+}
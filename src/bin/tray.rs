@@ -1,34 +1,43 @@
 use anyhow::Result;
 use ksni::menu::StandardItem;
 use ksni::{MenuItem, Tray, TrayService};
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::sync::mpsc;
-use zmk_battery_monitor::{Config, ZmkBatteryReader};
-
-enum Command {
-    Refresh,
-    Quit,
-}
+use zmk_battery_monitor::config::{TierConfig, Urgency};
+use zmk_battery_monitor::format::format_device_state;
+use zmk_battery_monitor::{
+    BackendState, ChargeEvent, Config, DeviceStatus, History, HookRunner, Monitor, MonitorEvent,
+    Notifier, RefreshHandle, ZmkBatteryReader,
+};
 
 struct BatteryTray {
-    battery_info: Arc<Mutex<String>>,
-    tx: mpsc::UnboundedSender<Command>,
-    device_name: String,
+    devices: Arc<Mutex<Vec<DeviceStatus>>>,
+    tiers: BTreeMap<String, TierConfig>,
+    refresh: RefreshHandle,
 }
 
 impl BatteryTray {
     fn new(
-        battery_info: Arc<Mutex<String>>,
-        tx: mpsc::UnboundedSender<Command>,
-        device_name: String,
+        devices: Arc<Mutex<Vec<DeviceStatus>>>,
+        tiers: BTreeMap<String, TierConfig>,
+        refresh: RefreshHandle,
     ) -> Self {
         Self {
-            battery_info,
-            tx,
-            device_name,
+            devices,
+            tiers,
+            refresh,
         }
     }
+
+    fn device_names(&self) -> String {
+        let devices = self.devices.lock().unwrap();
+        devices
+            .iter()
+            .map(|d| d.device.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
 }
 
 impl Tray for BatteryTray {
@@ -36,15 +45,53 @@ impl Tray for BatteryTray {
         "battery".to_string()
     }
 
+    fn status(&self) -> ksni::Status {
+        // Ask for attention while any battery is in a critical tier
+        let devices = self.devices.lock().unwrap();
+        let critical = devices.iter().any(|state| {
+            state.batteries.iter().any(|b| {
+                state
+                    .device
+                    .tier(b, &self.tiers)
+                    .is_some_and(|tier| tier.urgency == Urgency::Critical)
+            })
+        });
+        if critical {
+            ksni::Status::NeedsAttention
+        } else {
+            ksni::Status::Active
+        }
+    }
+
     fn title(&self) -> String {
-        format!("ZMK Battery - {}", self.device_name)
+        format!("ZMK Battery - {}", self.device_names())
     }
 
     fn tool_tip(&self) -> ksni::ToolTip {
-        let info = self.battery_info.lock().unwrap();
+        let devices = self.devices.lock().unwrap();
+        let (title, description) = match devices.as_slice() {
+            [state] => (
+                format!("{} Battery", state.device.name),
+                format_device_state(state, &self.tiers),
+            ),
+            _ => (
+                "ZMK Battery".to_string(),
+                devices
+                    .iter()
+                    .map(|state| {
+                        format!(
+                            "{}\n{}",
+                            state.device.name,
+                            format_device_state(state, &self.tiers)
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n\n"),
+            ),
+        };
         ksni::ToolTip {
-            title: format!("{} Battery", self.device_name),
-            description: info.clone(),
+            title,
+            description,
             ..Default::default()
         }
     }
@@ -54,18 +101,14 @@ impl Tray for BatteryTray {
             MenuItem::Standard(StandardItem {
                 label: "Refresh".to_string(),
                 enabled: true,
-                activate: Box::new(|tray: &mut Self| {
-                    let _ = tray.tx.send(Command::Refresh);
-                }),
+                activate: Box::new(|tray: &mut Self| tray.refresh.request()),
                 ..Default::default()
             }),
             MenuItem::Separator,
             MenuItem::Standard(StandardItem {
                 label: "Quit".to_string(),
                 enabled: true,
-                activate: Box::new(|tray: &mut Self| {
-                    let _ = tray.tx.send(Command::Quit);
-                }),
+                activate: Box::new(|_: &mut Self| std::process::exit(0)),
                 ..Default::default()
             }),
         ]
@@ -73,43 +116,7 @@ impl Tray for BatteryTray {
 
     fn activate(&mut self, _x: i32, _y: i32) {
         // Click on tray icon refreshes battery status
-        let _ = self.tx.send(Command::Refresh);
-    }
-}
-
-async fn update_battery_info(
-    battery_info: Arc<Mutex<String>>,
-    device_address: &str,
-    low_threshold: u8,
-) {
-    match read_battery(device_address, low_threshold).await {
-        Ok(info) => {
-            let mut data = battery_info.lock().unwrap();
-            *data = info;
-        }
-        Err(e) => {
-            let mut data = battery_info.lock().unwrap();
-            *data = format!("Error: {e}");
-        }
-    }
-}
-
-async fn read_battery(device_address: &str, low_threshold: u8) -> Result<String> {
-    let reader = ZmkBatteryReader::new().await?;
-    let batteries = reader.read_battery_levels(device_address).await?;
-
-    if batteries.is_empty() {
-        Ok("No battery data available".to_string())
-    } else {
-        let info = batteries
-            .iter()
-            .map(|b| {
-                let warning = if b.level <= low_threshold { " ⚠" } else { "" };
-                format!("{}: {}%{}", b.name, b.level, warning)
-            })
-            .collect::<Vec<_>>()
-            .join("\n");
-        Ok(info)
+        self.refresh.request();
     }
 }
 
@@ -127,67 +134,100 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
-    // Get the primary enabled device
-    let device = match config.get_primary_device() {
-        Some(d) => d,
-        None => {
-            eprintln!("No enabled devices found in config!");
-            eprintln!(
-                "Please edit the config file at: {}",
-                Config::config_path()?.display()
-            );
-            return Ok(());
-        }
-    };
+    if config.get_enabled_devices().is_empty() {
+        eprintln!("No enabled devices found in config!");
+        eprintln!(
+            "Please edit the config file at: {}",
+            Config::config_path()?.display()
+        );
+        return Ok(());
+    }
 
-    let device_address = device.address.clone();
-    let device_name = device.name.clone();
-    let low_threshold = device.low_battery_threshold;
     let update_interval = Duration::from_secs(config.general.update_interval);
 
-    let battery_info = Arc::new(Mutex::new("Loading...".to_string()));
-
-    // Initial battery read
-    update_battery_info(Arc::clone(&battery_info), &device_address, low_threshold).await;
+    let reader = ZmkBatteryReader::new()
+        .await?
+        .with_retry_policy(config.general.retry_policy());
+    let history = History::open_enabled(&config.history).unwrap_or_else(|e| {
+        eprintln!("Battery history disabled: {e:#}");
+        None
+    });
+    let mut monitor = Monitor::start_with_history(Box::new(reader), config.clone(), history).await;
+    let devices = monitor.devices();
+    let mut notifier = if config.notifications.enabled {
+        Notifier::new(&config)
+            .await
+            .map_err(|e| eprintln!("Desktop notifications disabled: {e}"))
+            .ok()
+    } else {
+        None
+    };
+    let mut hooks = HookRunner::new(&config);
 
-    // Create channel for commands
-    let (tx, mut rx) = mpsc::unbounded_channel();
+    for state in devices.lock().unwrap().iter() {
+        if !state.is_subscribed() && state.address.is_some() {
+            eprintln!(
+                "Battery notifications unavailable for {}, polling only",
+                state.device.name
+            );
+        }
+    }
 
     // Create tray service
-    let tray = BatteryTray::new(Arc::clone(&battery_info), tx, device_name.clone());
+    let tray = BatteryTray::new(
+        Arc::clone(&devices),
+        config.tiers.clone(),
+        monitor.refresh_handle(),
+    );
     let service = TrayService::new(tray);
     let handle = service.handle();
     service.spawn();
 
-    println!(
-        "Battery monitor tray started for: {} ({})",
-        device_name, device_address
-    );
+    for device in config.get_enabled_devices() {
+        println!(
+            "Battery monitor tray started for: {} ({})",
+            device.name,
+            device.selector()
+        );
+    }
     println!("Update interval: {} seconds", update_interval.as_secs());
     println!("Config file: {}", Config::config_path()?.display());
 
-    // Handle commands and periodic updates
-    let info_clone = Arc::clone(&battery_info);
-    let mut interval = tokio::time::interval(update_interval);
-    let device_addr_clone = device_address.clone();
-
+    // The monitor polls, follows the devices and refreshes when the tray
+    // asks it to; redraw after every change
     loop {
-        tokio::select! {
-            Some(cmd) = rx.recv() => {
-                match cmd {
-                    Command::Refresh => {
-                        update_battery_info(Arc::clone(&battery_info), &device_address, low_threshold).await;
-                        handle.update(|_| {});
+        let event = monitor.next().await;
+        hooks.handle(&event, &devices);
+        if let Some(notifier) = notifier.as_mut() {
+            if let Err(e) = notifier.handle(&event, &devices).await {
+                eprintln!("Failed to send notification: {e}");
+            }
+        }
+        match event {
+            MonitorEvent::Backend(BackendState::Unavailable) => {
+                eprintln!("Bluetooth service stopped, waiting for it to return");
+            }
+            MonitorEvent::Backend(BackendState::Available) => {
+                println!("Bluetooth service is back");
+            }
+            MonitorEvent::Charge {
+                device,
+                battery,
+                event,
+            } => {
+                let name = &devices.lock().unwrap()[device].device.name;
+                match event {
+                    ChargeEvent::Started { level } => {
+                        println!("{name} {battery}: charging started at {level}%");
                     }
-                    Command::Quit => {
-                        std::process::exit(0);
+                    ChargeEvent::Charged { level } => {
+                        println!("{name} {battery}: charged to {level}%");
                     }
                 }
             }
-            _ = interval.tick() => {
-                update_battery_info(Arc::clone(&info_clone), &device_addr_clone, low_threshold).await;
-                handle.update(|_| {});
-            }
+            MonitorEvent::HistoryFailed(e) => eprintln!("Failed to record battery history: {e}"),
+            _ => {}
         }
+        handle.update(|_| {});
     }
 }
@@ -1,15 +1,34 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::hooks::HookEvent;
+use crate::{
+    sort_batteries, BatteryInfo, BdAddr, DeviceInfo, ReadOptions, ReadStrategy, RetryPolicy,
+    ZmkError,
+};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Config {
     #[serde(default)]
     pub general: GeneralConfig,
     pub devices: Vec<DeviceConfig>,
     #[serde(default)]
     pub tray: TrayConfig,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    #[serde(default)]
+    pub notifications: NotificationConfig,
+    /// Threshold tiers by name; "warning" and "critical" are built in
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tiers: BTreeMap<String, TierConfig>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<HookConfig>,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,16 +37,69 @@ pub struct GeneralConfig {
     pub update_interval: u64, // seconds
     #[serde(default = "default_log_level")]
     pub log_level: String,
+    #[serde(default = "default_read_timeout_ms")]
+    pub read_timeout_ms: u64, // milliseconds, per D-Bus call
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_retry_backoff_ms")]
+    pub retry_backoff_ms: u64, // milliseconds, doubled on every retry
+    #[serde(default = "default_max_concurrent_reads")]
+    pub max_concurrent_reads: usize,
+    #[serde(default = "default_device_timeout_ms")]
+    pub device_timeout_ms: u64, // milliseconds, for reading all batteries of one device
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64, // milliseconds, for connecting with auto_connect
+    #[serde(default = "default_max_concurrent_hooks")]
+    pub max_concurrent_hooks: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceConfig {
     pub name: String,
-    pub address: String,
+    /// Identifies the device; if unset, it is looked up with the `match_*`
+    /// settings instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<BdAddr>,
+    /// Exact `Name` the device advertises
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub match_name: Option<String>,
+    /// Exact `Alias` of the device, e.g. as set with bluetoothctl
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub match_alias: Option<String>,
+    /// Glob pattern (`*` and `?`) matched against the name and the alias
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub match_pattern: Option<String>,
+    /// Adapter the device is paired on (e.g. "hci1"); any adapter if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adapter: Option<String>,
     #[serde(default = "default_true")]
     pub enabled: bool,
+    /// Level of the "warning" tier, unless `thresholds` sets one
     #[serde(default = "default_low_battery_threshold")]
     pub low_battery_threshold: u8,
+    /// Levels of threshold tiers by tier name, e.g. `critical = 10`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub thresholds: BTreeMap<String, u8>,
+    /// Per-battery overrides of `thresholds`, keyed like `battery_names`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub battery_thresholds: BTreeMap<String, BTreeMap<String, u8>>,
+    /// Display names for batteries, keyed by "central", "peripheral<N>" or
+    /// the name the device reports
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub battery_names: BTreeMap<String, String>,
+    /// Overrides of the `[general]` timeout and retry settings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_timeout_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_retries: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_backoff_ms: Option<u64>,
+    /// "cached", "live" or "cached-then-live"; live if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_strategy: Option<ReadStrategy>,
+    /// Connect the keyboard if it is paired but disconnected
+    #[serde(default = "default_false")]
+    pub auto_connect: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,11 +112,161 @@ pub struct TrayConfig {
     pub icon_theme: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// History file; `history.jsonl` in the data directory if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// Days samples are kept for; 0 keeps them forever
+    #[serde(default = "default_retention_days")]
+    pub retention_days: u32,
+}
+
+/// Prometheus metrics served by the daemon
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MetricsConfig {
+    /// Address to serve `/metrics` on, e.g. "127.0.0.1:9586"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listen: Option<String>,
+    /// node_exporter textfile collector directory to keep
+    /// `zmk_battery_monitor.prom` up to date in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub textfile_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotificationConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Points above the threshold a battery must climb back to before it
+    /// counts as recovered
+    #[serde(default = "default_hysteresis")]
+    pub hysteresis: u8,
+    /// Minutes between repeated low battery notifications; 0 notifies once
+    #[serde(default = "default_repeat_interval_minutes")]
+    pub repeat_interval_minutes: u64,
+    #[serde(default = "default_true")]
+    pub notify_recovered: bool,
+    /// Notify when a battery has finished charging
+    #[serde(default = "default_true")]
+    pub notify_charged: bool,
+}
+
+/// External command run on battery events
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HookConfig {
+    /// Run with `sh -c`
+    pub command: String,
+    /// Events that run the command; every event if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<HookEvent>,
+    /// Names of the devices whose events run the command; every device if
+    /// empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<String>,
+    /// The command is killed after this many milliseconds
+    #[serde(default = "default_hook_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+/// How a threshold tier is reported
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TierConfig {
+    /// Level at or below which the tier applies, for devices that set none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub urgency: Option<Urgency>,
+    /// Exit code of the CLI while a battery is in this tier
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    /// Status shown in the tray and notifications, e.g. "Critical"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+/// Urgency of a notification, as in the desktop notification spec
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Urgency {
+    Low,
+    #[default]
+    Normal,
+    Critical,
+}
+
+/// A threshold tier as it applies to one battery
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tier {
+    pub name: String,
+    /// The tier applies at or below this level
+    pub level: u8,
+    pub urgency: Urgency,
+    pub exit_code: i32,
+    pub label: String,
+}
+
+pub const WARNING_TIER: &str = "warning";
+pub const CRITICAL_TIER: &str = "critical";
+
+impl Tier {
+    /// Tier `name` at `level`, reported as configured in `definition` or
+    /// else as the built-in tier of that name
+    fn new(name: &str, level: u8, definition: Option<&TierConfig>) -> Self {
+        let (urgency, exit_code, label) = match name {
+            WARNING_TIER => (Urgency::Normal, 1, "Low"),
+            CRITICAL_TIER => (Urgency::Critical, 2, "Critical"),
+            _ => (Urgency::Normal, 1, name),
+        };
+        Self {
+            name: name.to_string(),
+            level,
+            urgency: definition.and_then(|d| d.urgency).unwrap_or(urgency),
+            exit_code: definition.and_then(|d| d.exit_code).unwrap_or(exit_code),
+            label: definition
+                .and_then(|d| d.label.clone())
+                .unwrap_or_else(|| label.to_string()),
+        }
+    }
+}
+
 impl Default for GeneralConfig {
     fn default() -> Self {
         Self {
             update_interval: default_update_interval(),
             log_level: default_log_level(),
+            read_timeout_ms: default_read_timeout_ms(),
+            max_retries: default_max_retries(),
+            retry_backoff_ms: default_retry_backoff_ms(),
+            max_concurrent_reads: default_max_concurrent_reads(),
+            device_timeout_ms: default_device_timeout_ms(),
+            connect_timeout_ms: default_connect_timeout_ms(),
+            max_concurrent_hooks: default_max_concurrent_hooks(),
+        }
+    }
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            address: None,
+            match_name: None,
+            match_alias: None,
+            match_pattern: None,
+            adapter: None,
+            enabled: default_true(),
+            low_battery_threshold: default_low_battery_threshold(),
+            battery_names: BTreeMap::new(),
+            thresholds: BTreeMap::new(),
+            battery_thresholds: BTreeMap::new(),
+            read_timeout_ms: None,
+            max_retries: None,
+            retry_backoff_ms: None,
+            read_strategy: None,
+            auto_connect: default_false(),
         }
     }
 }
@@ -59,12 +281,24 @@ impl Default for TrayConfig {
     }
 }
 
-impl Default for Config {
+impl Default for HistoryConfig {
     fn default() -> Self {
         Self {
-            general: GeneralConfig::default(),
-            devices: vec![],
-            tray: TrayConfig::default(),
+            enabled: default_true(),
+            path: None,
+            retention_days: default_retention_days(),
+        }
+    }
+}
+
+impl Default for NotificationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_true(),
+            hysteresis: default_hysteresis(),
+            repeat_interval_minutes: default_repeat_interval_minutes(),
+            notify_recovered: default_true(),
+            notify_charged: default_true(),
         }
     }
 }
@@ -78,6 +312,38 @@ fn default_log_level() -> String {
     "info".to_string()
 }
 
+fn default_read_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    250
+}
+
+fn default_max_concurrent_reads() -> usize {
+    4
+}
+
+fn default_device_timeout_ms() -> u64 {
+    30000
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_max_concurrent_hooks() -> usize {
+    4
+}
+
+fn default_hook_timeout_ms() -> u64 {
+    10000
+}
+
 fn default_true() -> bool {
     true
 }
@@ -94,6 +360,256 @@ fn default_icon_theme() -> String {
     "battery".to_string()
 }
 
+fn default_retention_days() -> u32 {
+    30
+}
+
+fn default_hysteresis() -> u8 {
+    5
+}
+
+fn default_repeat_interval_minutes() -> u64 {
+    60
+}
+
+/// Match `text` against a glob pattern where `*` matches any run of
+/// characters and `?` any single character
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position after the last `*` and the text position it was tried at
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, t));
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` swallow one more character
+                Some((star_p, star_t)) => {
+                    p = star_p;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+impl HistoryConfig {
+    /// Where samples are stored
+    pub fn path(&self) -> Result<PathBuf> {
+        match &self.path {
+            Some(path) => Ok(path.clone()),
+            None => Ok(dirs::data_dir()
+                .context("Failed to get data directory")?
+                .join("zmk-battery-monitor")
+                .join("history.jsonl")),
+        }
+    }
+}
+
+impl NotificationConfig {
+    /// Time between repeated low battery notifications, if they repeat
+    pub fn repeat_interval(&self) -> Option<Duration> {
+        (self.repeat_interval_minutes > 0)
+            .then(|| Duration::from_secs(self.repeat_interval_minutes * 60))
+    }
+}
+
+impl GeneralConfig {
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            timeout: Duration::from_millis(self.read_timeout_ms),
+            max_retries: self.max_retries,
+            initial_backoff: Duration::from_millis(self.retry_backoff_ms),
+        }
+    }
+}
+
+impl DeviceConfig {
+    /// Read settings for this device, falling back to `[general]`
+    pub fn read_options(&self, general: &GeneralConfig) -> ReadOptions {
+        ReadOptions {
+            adapter: self.adapter.clone(),
+            retry: Some(RetryPolicy {
+                timeout: Duration::from_millis(
+                    self.read_timeout_ms.unwrap_or(general.read_timeout_ms),
+                ),
+                max_retries: self.max_retries.unwrap_or(general.max_retries),
+                initial_backoff: Duration::from_millis(
+                    self.retry_backoff_ms.unwrap_or(general.retry_backoff_ms),
+                ),
+            }),
+            strategy: self.read_strategy,
+            connect_timeout: self
+                .auto_connect
+                .then(|| Duration::from_millis(general.connect_timeout_ms)),
+        }
+    }
+
+    /// Whether a known device matches every `match_*` setting given
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        let names = [&device.name, &device.alias];
+        self.match_name
+            .as_ref()
+            .is_none_or(|name| device.name.as_ref() == Some(name))
+            && self
+                .match_alias
+                .as_ref()
+                .is_none_or(|alias| device.alias.as_ref() == Some(alias))
+            && self.match_pattern.as_ref().is_none_or(|pattern| {
+                names
+                    .into_iter()
+                    .flatten()
+                    .any(|name| glob_match(pattern, name))
+            })
+            && self
+                .adapter
+                .as_ref()
+                .is_none_or(|adapter| *adapter == device.adapter)
+    }
+
+    /// Address of this device: the configured one, or else the first of
+    /// `known` that matches, preferring connected devices
+    pub fn resolve_address(&self, known: &[DeviceInfo]) -> Result<BdAddr, ZmkError> {
+        if let Some(address) = self.address {
+            return Ok(address);
+        }
+        known
+            .iter()
+            .filter(|device| device.paired || device.bonded)
+            .filter(|device| self.matches(device))
+            .min_by_key(|device| !device.connected)
+            .map(|device| device.address)
+            .ok_or_else(|| ZmkError::NoMatchingDevice(self.name.clone()))
+    }
+
+    /// How the device is identified, e.g. `name "Corne"`, for messages
+    pub fn selector(&self) -> String {
+        if let Some(address) = self.address {
+            return address.to_string();
+        }
+        [
+            ("name", &self.match_name),
+            ("alias", &self.match_alias),
+            ("pattern", &self.match_pattern),
+        ]
+        .into_iter()
+        .filter_map(|(label, value)| value.as_ref().map(|v| format!("{label} {v:?}")))
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+
+    /// Whether the device can be found at all
+    fn is_identifiable(&self) -> bool {
+        self.address.is_some()
+            || self.match_name.is_some()
+            || self.match_alias.is_some()
+            || self.match_pattern.is_some()
+    }
+
+    /// Rename batteries according to `battery_names` and restore the stable
+    /// Central-then-peripherals order
+    pub fn apply_battery_names(&self, batteries: &mut [BatteryInfo]) {
+        for battery in batteries.iter_mut() {
+            battery.name = self.battery_name(battery);
+        }
+        sort_batteries(batteries);
+    }
+
+    /// Threshold tiers of one battery, most severe (lowest level) first.
+    ///
+    /// A tier's level comes from `battery_thresholds`, then `thresholds`,
+    /// then `low_battery_threshold` for "warning", then the `level` of its
+    /// definition in `definitions`; tiers without a level do not apply.
+    pub fn tiers(
+        &self,
+        battery: &BatteryInfo,
+        definitions: &BTreeMap<String, TierConfig>,
+    ) -> Vec<Tier> {
+        let overrides = battery
+            .source
+            .key()
+            .and_then(|key| self.battery_thresholds.get(&key))
+            .or_else(|| self.battery_thresholds.get(&battery.name));
+
+        let mut names = BTreeSet::from([WARNING_TIER]);
+        names.extend(
+            definitions
+                .iter()
+                .filter(|(_, tier)| tier.level.is_some())
+                .map(|(name, _)| name.as_str()),
+        );
+        names.extend(self.thresholds.keys().map(String::as_str));
+        names.extend(
+            overrides
+                .into_iter()
+                .flat_map(|o| o.keys().map(String::as_str)),
+        );
+
+        let mut tiers: Vec<_> = names
+            .into_iter()
+            .filter_map(|name| {
+                let definition = definitions.get(name);
+                let level = overrides
+                    .and_then(|o| o.get(name))
+                    .or_else(|| self.thresholds.get(name))
+                    .copied()
+                    .or_else(|| (name == WARNING_TIER).then_some(self.low_battery_threshold))
+                    .or_else(|| definition?.level)?;
+                Some(Tier::new(name, level, definition))
+            })
+            .collect();
+        tiers.sort_by_key(|tier| tier.level);
+        tiers
+    }
+
+    /// Most severe tier `battery` is in, if any
+    pub fn tier(
+        &self,
+        battery: &BatteryInfo,
+        definitions: &BTreeMap<String, TierConfig>,
+    ) -> Option<Tier> {
+        self.tiers(battery, definitions)
+            .into_iter()
+            .find(|tier| battery.level <= tier.level)
+    }
+
+    /// Level of the "warning" tier of one battery, which time-to-low
+    /// estimates count down to
+    pub fn warning_level(
+        &self,
+        battery: &BatteryInfo,
+        definitions: &BTreeMap<String, TierConfig>,
+    ) -> u8 {
+        self.tiers(battery, definitions)
+            .into_iter()
+            .find(|tier| tier.name == WARNING_TIER)
+            .map_or(self.low_battery_threshold, |tier| tier.level)
+    }
+
+    /// Display name of one battery according to `battery_names`
+    pub fn battery_name(&self, battery: &BatteryInfo) -> String {
+        battery
+            .source
+            .key()
+            .and_then(|key| self.battery_names.get(&key))
+            .or_else(|| self.battery_names.get(&battery.name))
+            .cloned()
+            .unwrap_or_else(|| battery.name.clone())
+    }
+}
+
 impl Config {
     /// Load config from the default location or create a default one
     pub fn load() -> Result<Self> {
@@ -119,6 +635,14 @@ impl Config {
         let config: Config = toml::from_str(&contents)
             .with_context(|| format!("Failed to parse config file: {}", path.display()))?;
 
+        if let Some(device) = config.devices.iter().find(|d| !d.is_identifiable()) {
+            bail!(
+                "Device \"{}\" in {} needs an address, match_name, match_alias or match_pattern",
+                device.name,
+                path.display()
+            );
+        }
+
         Ok(config)
     }
 
@@ -157,18 +681,25 @@ impl Config {
             devices: vec![
                 DeviceConfig {
                     name: "Example Keyboard".to_string(),
-                    address: "00:00:00:00:00:00".to_string(),
+                    address: Some(BdAddr::default()),
                     enabled: false,
                     low_battery_threshold: 20,
+                    ..Default::default()
                 },
                 DeviceConfig {
                     name: "Krypton-KBD".to_string(),
-                    address: "D2:75:8A:E6:6A:FD".to_string(),
+                    address: Some(BdAddr::new([0xD2, 0x75, 0x8A, 0xE6, 0x6A, 0xFD])),
                     enabled: true,
                     low_battery_threshold: 20,
+                    ..Default::default()
                 },
             ],
             tray: TrayConfig::default(),
+            history: HistoryConfig::default(),
+            notifications: NotificationConfig::default(),
+            tiers: BTreeMap::new(),
+            hooks: Vec::new(),
+            metrics: MetricsConfig::default(),
         }
     }
 
@@ -191,6 +722,19 @@ impl Config {
 update_interval = 60
 # Log level: trace, debug, info, warn, error
 log_level = "info"
+# Timeout for each Bluetooth read in milliseconds
+read_timeout_ms = 5000
+# Retries when a keyboard half is asleep or BlueZ is busy
+max_retries = 3
+# Delay before the first retry in milliseconds, doubled on each retry
+retry_backoff_ms = 250
+# Devices read at the same time, and total time allowed per device
+max_concurrent_reads = 4
+device_timeout_ms = 30000
+# Time allowed for connecting devices with auto_connect in milliseconds
+connect_timeout_ms = 10000
+# Hook commands run at the same time; further ones wait their turn
+max_concurrent_hooks = 4
 
 # Define your keyboards here
 # You can have multiple devices and enable/disable them individually
@@ -198,8 +742,37 @@ log_level = "info"
 [[devices]]
 name = "My ZMK Keyboard"
 address = "00:00:00:00:00:00"  # Replace with your keyboard's MAC address
+# Instead of an address, the keyboard can be found by its advertised name, its
+# alias, or a glob pattern on either; useful when it gets re-paired
+# match_name = "Corne"
+# match_alias = "Work keyboard"
+# match_pattern = "Corne*"
+# adapter = "hci1"  # Only look on this adapter (default: any adapter)
 enabled = true
+# Level of the "warning" tier
 low_battery_threshold = 20
+# The timeout and retry settings from [general] can be overridden per device
+# read_timeout_ms = 10000
+# max_retries = 5
+# Where to get levels from: "live" reads the keyboard (default), "cached" only
+# uses the value BlueZ already has, "cached-then-live" reads if there is none
+# read_strategy = "cached-then-live"
+# Connect the keyboard when it is paired but disconnected
+# auto_connect = true
+
+# More tiers, e.g. "critical" or your own defined under [tiers] below
+# [devices.thresholds]
+# critical = 10
+# Tier levels for single batteries, keyed like battery_names
+# [devices.battery_thresholds.peripheral0]
+# critical = 15
+
+# Optional display names for the batteries of split keyboards and dongles
+# [devices.battery_names]
+# central = "Dongle"
+# peripheral0 = "Left"
+# peripheral1 = "Right"
+
 
 # Example of a second keyboard (disabled)
 # [[devices]]
@@ -212,7 +785,400 @@ low_battery_threshold = 20
 enabled = true
 show_percentage_in_tray = false
 icon_theme = "battery"  # Icon name for system tray
+
+[history]
+# Record battery levels to ~/.local/share/zmk-battery-monitor/history.jsonl
+enabled = true
+# path = "/path/to/history.jsonl"
+# Days to keep samples for; 0 keeps them forever
+retention_days = 30
+
+# How each tier is reported. "warning" (exit code 1) and "critical" (urgency
+# critical, exit code 2) are built in; other names define new tiers. A level
+# here applies to devices that do not set their own.
+# [tiers.critical]
+# level = 10
+# [tiers.reserve]
+# level = 5
+# urgency = "critical"  # low, normal or critical
+# exit_code = 3
+# label = "Nearly empty"
+
+[notifications]
+# Desktop notifications when a battery drops into a threshold tier
+enabled = true
+# A low battery counts as recovered once it is this many points above the
+# threshold, so a level bouncing around the threshold notifies only once
+hysteresis = 5
+# Remind again after this many minutes while still low; 0 notifies once
+repeat_interval_minutes = 60
+notify_recovered = true
+# Notify when a battery is charged, i.e. it is safe to unplug it
+notify_charged = true
+
+# Commands run on battery events, with the details in ZMK_EVENT, ZMK_DEVICE,
+# ZMK_ADDRESS, ZMK_BATTERY, ZMK_LEVEL, ZMK_TIER and ZMK_ERROR. Events are
+# threshold, recovered, connected, disconnected, charging-started, charged and
+# read-error; every event runs the command if none are given.
+# [[hooks]]
+# command = "notify-desk-light red"
+# events = ["threshold"]
+# devices = ["My ZMK Keyboard"]
+# timeout_ms = 10000
+
+[metrics]
+# Serve Prometheus metrics on http://<listen>/metrics from the daemon
+# listen = "127.0.0.1:9586"
+# Or keep a file up to date for the node_exporter textfile collector
+# textfile_dir = "/var/lib/node_exporter/textfile_collector"
 "#;
         template.to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BatterySource, ReadOrigin};
+
+    #[test]
+    fn glob_patterns() {
+        for (pattern, text, expected) in [
+            ("Corne", "Corne", true),
+            ("Corne", "corne", false),
+            ("Corne", "Corne v3", false),
+            ("*", "", true),
+            ("*", "anything", true),
+            ("", "", true),
+            ("", "x", false),
+            ("Corne*", "Corne", true),
+            ("Corne*", "Corne v3", true),
+            ("*v3", "Corne v3", true),
+            ("*v3", "Corne v3 left", false),
+            ("C?rne", "Corne", true),
+            ("C?rne", "Crne", false),
+            ("???", "abc", true),
+            ("???", "ab", false),
+            ("*Corne*", "My Corne keyboard", true),
+            // `*` has to give back characters it first swallowed
+            ("*ab", "aab", true),
+            ("*ab*cd", "abxabcd", true),
+            ("a*b*c", "abbbbc", true),
+            ("a*b*c", "abbbbcx", false),
+            ("*a*a*a", "aa", false),
+            ("**", "x", true),
+            ("*?", "", false),
+            ("*?", "x", true),
+        ] {
+            assert_eq!(
+                glob_match(pattern, text),
+                expected,
+                "{pattern:?} against {text:?}"
+            );
+        }
+    }
+
+    fn known(name: &str, address: u8, adapter: &str, connected: bool) -> DeviceInfo {
+        DeviceInfo {
+            name: Some(name.to_string()),
+            alias: Some(format!("{name} alias")),
+            address: BdAddr::new([0xAA, 0xBB, 0xCC, 0xDD, 0xEE, address]),
+            adapter: adapter.to_string(),
+            paired: true,
+            connected,
+            ..Default::default()
+        }
+    }
+
+    fn resolve(device: &DeviceConfig, known: &[DeviceInfo]) -> Option<u8> {
+        device
+            .resolve_address(known)
+            .ok()
+            .map(|address| address.bytes()[5])
+    }
+
+    #[test]
+    fn configured_address_wins() {
+        let address = BdAddr::new([1, 2, 3, 4, 5, 6]);
+        let device = DeviceConfig {
+            address: Some(address),
+            match_name: Some("Corne".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(device.resolve_address(&[]).unwrap(), address);
+    }
+
+    #[test]
+    fn match_settings() {
+        let known = [
+            known("Corne", 1, "hci0", false),
+            known("Lily58", 2, "hci0", false),
+            known("Sofle", 3, "hci1", false),
+        ];
+        let by = |f: fn(&mut DeviceConfig)| {
+            let mut device = DeviceConfig {
+                name: "Keyboard".to_string(),
+                ..Default::default()
+            };
+            f(&mut device);
+            resolve(&device, &known)
+        };
+
+        assert_eq!(by(|d| d.match_name = Some("Lily58".into())), Some(2));
+        assert_eq!(by(|d| d.match_name = Some("lily58".into())), None);
+        assert_eq!(by(|d| d.match_alias = Some("Sofle alias".into())), Some(3));
+        assert_eq!(by(|d| d.match_alias = Some("Sofle".into())), None);
+        assert_eq!(by(|d| d.match_pattern = Some("L*8".into())), Some(2));
+        // Patterns also match the alias
+        assert_eq!(by(|d| d.match_pattern = Some("S* alias".into())), Some(3));
+        assert_eq!(
+            by(|d| {
+                d.match_pattern = Some("*".into());
+                d.adapter = Some("hci1".into());
+            }),
+            Some(3)
+        );
+        // Every setting given has to match
+        assert_eq!(
+            by(|d| {
+                d.match_name = Some("Corne".into());
+                d.adapter = Some("hci1".into());
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn connected_device_is_preferred() {
+        let device = DeviceConfig {
+            name: "Corne".to_string(),
+            match_pattern: Some("Corne*".to_string()),
+            ..Default::default()
+        };
+        let mut known = [
+            known("Corne old", 1, "hci0", false),
+            known("Corne", 2, "hci0", true),
+            known("Corne spare", 3, "hci1", true),
+        ];
+        // The first connected one
+        assert_eq!(resolve(&device, &known), Some(2));
+
+        // Without a connected one, the first match
+        known[1].connected = false;
+        known[2].connected = false;
+        assert_eq!(resolve(&device, &known), Some(1));
+    }
+
+    #[test]
+    fn unpaired_devices_do_not_match() {
+        let device = DeviceConfig {
+            name: "Corne".to_string(),
+            match_name: Some("Corne".to_string()),
+            ..Default::default()
+        };
+        let mut unpaired = known("Corne", 1, "hci0", true);
+        unpaired.paired = false;
+        assert!(matches!(
+            device.resolve_address(&[unpaired.clone()]),
+            Err(ZmkError::NoMatchingDevice(name)) if name == "Corne"
+        ));
+
+        unpaired.bonded = true;
+        assert_eq!(resolve(&device, &[unpaired]), Some(1));
+    }
+
+    #[test]
+    fn template_parses() {
+        let config: Config = toml::from_str(&Config::generate_template()).unwrap();
+        assert_eq!(config.devices.len(), 1);
+    }
+
+    #[test]
+    fn template_parses_with_every_option_uncommented() {
+        // Settings and table headers, not the prose comments around them
+        let setting = |line: &str| {
+            line.starts_with('[')
+                || line.split_once(" = ").is_some_and(|(key, _)| {
+                    key.chars()
+                        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+                })
+        };
+        let template: String = Config::generate_template()
+            .lines()
+            .map(|line| match line.strip_prefix("# ") {
+                Some(rest) if setting(rest) => format!("{rest}\n"),
+                _ => format!("{line}\n"),
+            })
+            .collect();
+
+        let config: Config = toml::from_str(&template).unwrap();
+        let device = &config.devices[0];
+        assert_eq!(device.read_timeout_ms, Some(10000));
+        assert_eq!(device.max_retries, Some(5));
+        assert_eq!(device.read_strategy, Some(ReadStrategy::CachedThenLive));
+        assert!(device.auto_connect);
+        assert_eq!(device.match_pattern.as_deref(), Some("Corne*"));
+        assert_eq!(device.thresholds["critical"], 10);
+        assert_eq!(device.battery_thresholds["peripheral0"]["critical"], 15);
+        assert_eq!(device.battery_names.len(), 3);
+        assert_eq!(config.devices.len(), 2);
+        assert_eq!(config.tiers.len(), 2);
+        assert_eq!(config.hooks.len(), 1);
+        assert!(config.metrics.listen.is_some());
+    }
+
+    fn battery(source: BatterySource, level: u8) -> BatteryInfo {
+        BatteryInfo {
+            name: "Battery".to_string(),
+            level,
+            source,
+            retries: 0,
+            origin: ReadOrigin::Device,
+            reconnected: false,
+            charging: Default::default(),
+        }
+    }
+
+    /// `(name, level)` of the tiers of `battery`
+    fn tier_levels(config: &Config, device: usize, battery: &BatteryInfo) -> Vec<(String, u8)> {
+        config.devices[device]
+            .tiers(battery, &config.tiers)
+            .into_iter()
+            .map(|tier| (tier.name, tier.level))
+            .collect()
+    }
+
+    fn tiers(list: &[(&str, u8)]) -> Vec<(String, u8)> {
+        list.iter()
+            .map(|&(name, level)| (name.to_string(), level))
+            .collect()
+    }
+
+    #[test]
+    fn warning_tier_alone_by_default() {
+        let config: Config = toml::from_str(
+            r#"
+            [[devices]]
+            name = "Corne"
+            low_battery_threshold = 25
+            "#,
+        )
+        .unwrap();
+        let device = &config.devices[0];
+        let central = battery(BatterySource::Central, 25);
+        assert_eq!(tier_levels(&config, 0, &central), tiers(&[("warning", 25)]));
+
+        let tier = device.tier(&central, &config.tiers).unwrap();
+        assert_eq!(
+            (tier.label.as_str(), tier.urgency, tier.exit_code),
+            ("Low", Urgency::Normal, 1)
+        );
+        let above = battery(BatterySource::Central, 26);
+        assert_eq!(device.tier(&above, &config.tiers), None);
+    }
+
+    #[test]
+    fn device_tiers_override_global_ones() {
+        let config: Config = toml::from_str(
+            r#"
+            [[devices]]
+            name = "Corne"
+            thresholds = { critical = 5 }
+            battery_thresholds = { peripheral0 = { critical = 15 } }
+
+            [[devices]]
+            name = "Lily58"
+
+            [tiers.critical]
+            level = 10
+            [tiers.reserve]
+            urgency = "critical"
+            label = "Nearly empty"
+            "#,
+        )
+        .unwrap();
+        let central = battery(BatterySource::Central, 50);
+        let peripheral = battery(BatterySource::Peripheral(0), 50);
+
+        assert_eq!(
+            tier_levels(&config, 0, &central),
+            tiers(&[("critical", 5), ("warning", 20)])
+        );
+        assert_eq!(
+            tier_levels(&config, 0, &peripheral),
+            tiers(&[("critical", 15), ("warning", 20)])
+        );
+        // The global level, and no "reserve" as nothing gives it a level
+        assert_eq!(
+            tier_levels(&config, 1, &central),
+            tiers(&[("critical", 10), ("warning", 20)])
+        );
+    }
+
+    #[test]
+    fn tiers_are_ordered_by_severity() {
+        let config: Config = toml::from_str(
+            r#"
+            [[devices]]
+            name = "Corne"
+            low_battery_threshold = 30
+            thresholds = { critical = 10, reserve = 5, caution = 40 }
+
+            [tiers.reserve]
+            exit_code = 3
+            "#,
+        )
+        .unwrap();
+        let device = &config.devices[0];
+        let central = battery(BatterySource::Central, 50);
+        assert_eq!(
+            tier_levels(&config, 0, &central),
+            tiers(&[
+                ("reserve", 5),
+                ("critical", 10),
+                ("warning", 30),
+                ("caution", 40)
+            ])
+        );
+
+        // The most severe tier a level is in
+        let tier_at = |level| {
+            device
+                .tier(&battery(BatterySource::Central, level), &config.tiers)
+                .map(|tier| (tier.name, tier.exit_code))
+        };
+        assert_eq!(tier_at(4), Some(("reserve".to_string(), 3)));
+        assert_eq!(tier_at(10), Some(("critical".to_string(), 2)));
+        assert_eq!(tier_at(25), Some(("warning".to_string(), 1)));
+        assert_eq!(tier_at(40), Some(("caution".to_string(), 1)));
+        assert_eq!(tier_at(41), None);
+    }
+
+    #[test]
+    fn warning_level_follows_the_tiers() {
+        let config: Config = toml::from_str(
+            r#"
+            [[devices]]
+            name = "Corne"
+            low_battery_threshold = 15
+
+            [[devices]]
+            name = "Lily58"
+            thresholds = { warning = 30 }
+            battery_thresholds = { peripheral1 = { warning = 25 } }
+            "#,
+        )
+        .unwrap();
+        let (central, peripheral) = (
+            battery(BatterySource::Central, 50),
+            battery(BatterySource::Peripheral(1), 50),
+        );
+        let [corne, lily] = &config.devices[..] else {
+            panic!("two devices expected");
+        };
+
+        assert_eq!(corne.warning_level(&central, &config.tiers), 15);
+        assert_eq!(lily.warning_level(&central, &config.tiers), 30);
+        assert_eq!(lily.warning_level(&peripheral, &config.tiers), 25);
+    }
+}
@@ -0,0 +1,175 @@
+use futures_util::future;
+use futures_util::stream::{self, BoxStream, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::time::Instant;
+use zbus::message::Type;
+use zbus::zvariant::{OwnedObjectPath, Value};
+use zbus::{Connection, MatchRule, Message, MessageStream};
+
+use crate::tree::{Device, Properties};
+use crate::{DeviceInfo, Result, ZmkBatteryReader, ZmkError, BATTERY_UUID, HID_UUID};
+
+/// Stream of keyboards found while scanning
+pub type DiscoveryStream = BoxStream<'static, DeviceInfo>;
+
+impl ZmkBatteryReader {
+    /// Scan for ZMK keyboards for at most `duration`.
+    ///
+    /// Runs `StartDiscovery` on `adapter`, or on every adapter if unset, with
+    /// a discovery filter on the Battery Service and HID UUIDs. Each keyboard
+    /// is yielded once, when it is first seen; devices BlueZ already knows
+    /// show up as soon as they advertise. Discovery is stopped when the time
+    /// is up or the stream is dropped.
+    pub async fn discover(
+        &self,
+        duration: Duration,
+        adapter: Option<&str>,
+    ) -> Result<DiscoveryStream> {
+        let tree = self.object_tree().await?;
+        let adapters: Vec<_> = tree
+            .adapters
+            .iter()
+            .filter(|a| adapter.is_none_or(|name| name == a.name))
+            .map(|a| a.path.clone())
+            .collect();
+        if adapters.is_empty() {
+            return Err(ZmkError::AdapterNotFound(adapter.map(str::to_string)));
+        }
+
+        // Listen before scanning so that no advertisement is missed
+        let object_manager = zbus::Proxy::new(
+            &self.conn,
+            "org.bluez",
+            "/",
+            "org.freedesktop.DBus.ObjectManager",
+        )
+        .await?;
+        let added = object_manager
+            .receive_signal("InterfacesAdded")
+            .await?
+            .filter_map(|message| future::ready(new_device(&message)));
+
+        let rule = MatchRule::builder()
+            .msg_type(Type::Signal)
+            .sender("org.bluez")?
+            .interface("org.freedesktop.DBus.Properties")?
+            .member("PropertiesChanged")?
+            .path_namespace("/org/bluez")?
+            .arg(0, "org.bluez.Device1")?
+            .build();
+        let known: HashMap<_, _> = tree
+            .devices()
+            .map(|device| (device.path.clone(), DeviceInfo::from_device(device)))
+            .collect();
+        let seen_again = MessageStream::for_match_rule(rule, &self.conn, None)
+            .await?
+            .filter_map(move |message| {
+                future::ready(
+                    message
+                        .ok()
+                        .and_then(|message| advertising_device(&message, &known)),
+                )
+            });
+
+        let mut session = DiscoverySession {
+            conn: self.conn.clone(),
+            adapters: Vec::new(),
+        };
+        for path in adapters {
+            let proxy =
+                zbus::Proxy::new(&self.conn, "org.bluez", path.as_str(), "org.bluez.Adapter1")
+                    .await?;
+            let filter = HashMap::from([
+                ("UUIDs", Value::from(vec![BATTERY_UUID, HID_UUID])),
+                ("Transport", Value::from("le")),
+            ]);
+            proxy.call_method("SetDiscoveryFilter", &(filter,)).await?;
+            proxy.call_method("StartDiscovery", &()).await?;
+            session.adapters.push(path);
+        }
+
+        let found = stream::select(added, seen_again)
+            .filter(|device| future::ready(device.is_zmk_candidate()))
+            .boxed();
+        let deadline = Instant::now() + duration;
+
+        Ok(stream::unfold(
+            (found, session, HashSet::new()),
+            move |(mut found, mut session, mut seen)| async move {
+                while let Ok(Some(device)) = tokio::time::timeout_at(deadline, found.next()).await {
+                    if seen.insert(device.address) {
+                        return Some((device, (found, session, seen)));
+                    }
+                }
+                session.stop().await;
+                None
+            },
+        )
+        .boxed())
+    }
+}
+
+/// Adapters discovery was started on
+struct DiscoverySession {
+    conn: Connection,
+    adapters: Vec<String>,
+}
+
+impl DiscoverySession {
+    async fn stop(&mut self) {
+        stop_discovery(self.conn.clone(), std::mem::take(&mut self.adapters)).await;
+    }
+}
+
+impl Drop for DiscoverySession {
+    fn drop(&mut self) {
+        if self.adapters.is_empty() {
+            return;
+        }
+        // Dropped mid-scan; the D-Bus calls cannot be awaited here
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(stop_discovery(
+                self.conn.clone(),
+                std::mem::take(&mut self.adapters),
+            ));
+        }
+    }
+}
+
+async fn stop_discovery(conn: Connection, adapters: Vec<String>) {
+    for path in adapters {
+        if let Ok(proxy) =
+            zbus::Proxy::new(&conn, "org.bluez", path.as_str(), "org.bluez.Adapter1").await
+        {
+            // Fails harmlessly if the adapter went away in the meantime
+            let _ = proxy.call_method("StopDiscovery", &()).await;
+        }
+    }
+}
+
+/// Device from an `InterfacesAdded` signal for `org.bluez.Device1`
+fn new_device(message: &Message) -> Option<DeviceInfo> {
+    let (path, interfaces) = message
+        .body()
+        .deserialize::<(OwnedObjectPath, HashMap<String, Properties>)>()
+        .ok()?;
+    let props = interfaces.get("org.bluez.Device1")?;
+    let (_, device) = Device::parse(path.as_str(), props).ok()?;
+    Some(DeviceInfo::from_device(&device))
+}
+
+/// Already known device whose RSSI changed, i.e. that was just heard
+fn advertising_device(
+    message: &Message,
+    known: &HashMap<String, DeviceInfo>,
+) -> Option<DeviceInfo> {
+    let header = message.header();
+    let mut device = known.get(header.path()?.as_str())?.clone();
+    let (_interface, changed, _invalidated) = message
+        .body()
+        .deserialize::<(String, Properties, Vec<String>)>()
+        .ok()?;
+    device.rssi = Some(i16::try_from(changed.get("RSSI")?).ok()?);
+    Some(device)
+}
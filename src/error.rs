@@ -0,0 +1,110 @@
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::BdAddr;
+use zbus::{fdo, zvariant};
+
+/// Errors produced by `ZmkBatteryReader`
+#[derive(Debug, Clone, Error)]
+pub enum ZmkError {
+    #[error("BlueZ is not running (org.bluez is not available on the system bus)")]
+    BluezUnavailable,
+    #[error(
+        "No Bluetooth adapter{} found",
+        .0.as_ref().map(|name| format!(" named {name}")).unwrap_or_default()
+    )]
+    AdapterNotFound(Option<String>),
+    #[error("No paired device matches {0}")]
+    NoMatchingDevice(String),
+    #[error("Device {0} is not paired")]
+    NotPaired(BdAddr),
+    #[error("Device {0} is paired but not connected")]
+    Disconnected(BdAddr),
+    #[error("GATT services of device {0} are not resolved yet")]
+    ServicesNotResolved(BdAddr),
+    #[error("Device {0} has no Battery Service")]
+    NoBatteryService(BdAddr),
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("Timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("D-Bus error: {0}")]
+    DBus(#[source] zbus::Error),
+}
+
+impl ZmkError {
+    /// Whether the error is likely to go away on its own, e.g. once the
+    /// keyboard wakes up, so that retrying later makes sense
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::BluezUnavailable
+                | Self::Disconnected(_)
+                | Self::ServicesNotResolved(_)
+                | Self::Timeout(_)
+        )
+    }
+
+    /// Short name of the kind of error, e.g. for metric labels
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::BluezUnavailable => "bluez_unavailable",
+            Self::AdapterNotFound(_) => "adapter_not_found",
+            Self::NoMatchingDevice(_) => "no_matching_device",
+            Self::NotPaired(_) => "not_paired",
+            Self::Disconnected(_) => "disconnected",
+            Self::ServicesNotResolved(_) => "services_not_resolved",
+            Self::NoBatteryService(_) => "no_battery_service",
+            Self::PermissionDenied(_) => "permission_denied",
+            Self::Timeout(_) => "timeout",
+            Self::DBus(_) => "dbus",
+        }
+    }
+
+    /// Whether a single D-Bus call failing with this error is worth retrying
+    /// right away, e.g. because BlueZ is busy with another operation
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Timeout(_) => true,
+            Self::DBus(zbus::Error::MethodError(name, _, _)) => matches!(
+                name.as_str(),
+                "org.bluez.Error.InProgress"
+                    | "org.bluez.Error.NotReady"
+                    | "org.bluez.Error.Failed"
+                    | "org.freedesktop.DBus.Error.NoReply"
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl From<zbus::Error> for ZmkError {
+    fn from(err: zbus::Error) -> Self {
+        match &err {
+            zbus::Error::MethodError(name, detail, _) => match name.as_str() {
+                "org.freedesktop.DBus.Error.ServiceUnknown"
+                | "org.freedesktop.DBus.Error.NameHasNoOwner" => Self::BluezUnavailable,
+                "org.freedesktop.DBus.Error.AccessDenied"
+                | "org.bluez.Error.NotPermitted"
+                | "org.bluez.Error.NotAuthorized" => {
+                    Self::PermissionDenied(detail.clone().unwrap_or_else(|| name.to_string()))
+                }
+                _ => Self::DBus(err),
+            },
+            zbus::Error::FDO(fdo_err) => match fdo_err.as_ref() {
+                fdo::Error::ServiceUnknown(_) | fdo::Error::NameHasNoOwner(_) => {
+                    Self::BluezUnavailable
+                }
+                fdo::Error::AccessDenied(detail) => Self::PermissionDenied(detail.clone()),
+                _ => Self::DBus(err),
+            },
+            _ => Self::DBus(err),
+        }
+    }
+}
+
+impl From<zvariant::Error> for ZmkError {
+    fn from(err: zvariant::Error) -> Self {
+        Self::DBus(err.into())
+    }
+}
@@ -0,0 +1,505 @@
+//! Discharge rate, time-to-empty and charging detection.
+//!
+//! ZMK reports whole percent, so a battery sits at one level for hours and
+//! then drops a step. Fitting a line through every sample would weigh long
+//! plateaus by how often they happened to be polled; instead a `LevelTrend`
+//! keeps the first sample of each level plus the latest one, and the rate is
+//! a least-squares fit through those points.
+//!
+//! ZMK levels jitter by a point or two, so a single higher reading proves
+//! nothing. A rise of two points above the lowest level since the last charge
+//! only makes charging possible; it is confirmed once the level climbs two
+//! more points (or reaches 100%) without falling back below that reading.
+//! Charging ends at 100% or once the level falls two points below its peak,
+//! i.e. the keyboard was unplugged. The discharge segment before the charge
+//! is kept as a fallback rate until the new segment has enough data of its
+//! own.
+
+use std::time::{Duration, SystemTime};
+
+use crate::history::Sample;
+use crate::ChargeState;
+
+/// How far back history is used for estimates
+pub const ESTIMATE_WINDOW: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// Noise margin: a change of this many points or more against the trend may
+/// be charging starting or stopping rather than jitter
+const CHARGE_RISE: u8 = 2;
+/// Minimum drop and time span of a segment before its rate is trusted
+const MIN_DROP: u8 = 2;
+const MIN_SPAN: Duration = Duration::from_secs(60 * 60);
+
+/// Predicted discharge of one battery
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DischargeEstimate {
+    /// Percentage points lost per hour
+    pub rate: f64,
+    /// Time until the low battery threshold; `None` if already at or below it
+    pub until_low: Option<Duration>,
+    /// Time until 0%
+    pub until_empty: Duration,
+}
+
+/// Change of a battery's charge state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChargeEvent {
+    /// The level started rising
+    Started { level: u8 },
+    /// Charging ended at `level`, at 100% or when the keyboard was unplugged
+    Charged { level: u8 },
+}
+
+/// Level history of one battery since it was last charged
+#[derive(Debug, Clone, Default)]
+pub struct LevelTrend {
+    /// `(time, level)` of the first sample at each level, then the latest;
+    /// empty while charging
+    points: Vec<(SystemTime, u8)>,
+    /// Rate of the previous discharge segment, if it was usable
+    previous_rate: Option<f64>,
+    state: ChargeState,
+    /// Latest reading
+    latest: Option<(SystemTime, u8)>,
+    /// Lowest level while discharging, highest while charging
+    extreme: u8,
+    /// Reading that rose `CHARGE_RISE` above the lowest level, while it is
+    /// not yet known whether the battery is charging
+    rise: Option<u8>,
+}
+
+impl LevelTrend {
+    /// Trend of the given samples of one battery, oldest first
+    pub fn from_samples<'a>(samples: impl IntoIterator<Item = &'a Sample>) -> Self {
+        let mut trend = Self::default();
+        for sample in samples {
+            trend.push(sample.time, sample.level);
+        }
+        trend
+    }
+
+    /// Add a reading, returning a change of the charge state worth telling
+    /// the user about; readings older than the latest one are ignored
+    pub fn push(&mut self, time: SystemTime, level: u8) -> Option<ChargeEvent> {
+        let Some((last_time, last_level)) = self.latest else {
+            self.latest = Some((time, level));
+            self.extreme = level;
+            self.points.push((time, level));
+            return None;
+        };
+        if time < last_time {
+            return None;
+        }
+        self.latest = Some((time, level));
+
+        match self.state {
+            ChargeState::Unknown | ChargeState::Discharging => {
+                let confirmed = match self.rise {
+                    Some(from) => level >= from.saturating_add(CHARGE_RISE) || level >= 100,
+                    None => false,
+                };
+                if !confirmed && self.rise.is_none_or(|from| level < from) {
+                    // No rise yet, or it fell back: jitter, maybe a new rise
+                    self.rise =
+                        (level >= self.extreme.saturating_add(CHARGE_RISE)).then_some(level);
+                }
+                if confirmed {
+                    self.rise = None;
+                    if let Some(rate) = self.segment_rate() {
+                        self.previous_rate = Some(rate);
+                    }
+                    self.points.clear();
+                    self.extreme = level;
+                    self.state = if level >= 100 {
+                        ChargeState::Full
+                    } else {
+                        ChargeState::Charging
+                    };
+                    return Some(ChargeEvent::Started { level });
+                }
+                if level < last_level {
+                    self.state = ChargeState::Discharging;
+                }
+                self.extreme = self.extreme.min(level);
+                // Readings of an undecided rise are either jitter or charging,
+                // and belong to neither in the discharge fit
+                if self.rise.is_none() {
+                    self.push_point(time, level);
+                }
+                None
+            }
+            ChargeState::Charging | ChargeState::Full => {
+                if level.saturating_add(CHARGE_RISE) <= self.extreme {
+                    // Unplugged: a new discharge segment starts here. The
+                    // peak of a confirmed charge is at least twice the noise
+                    // margin above where it started, so this is never jitter
+                    let charged = self.state == ChargeState::Charging;
+                    let peak = self.extreme;
+                    self.state = ChargeState::Discharging;
+                    self.extreme = level;
+                    self.points.push((time, level));
+                    return charged.then_some(ChargeEvent::Charged { level: peak });
+                }
+                self.extreme = self.extreme.max(level);
+                if level >= 100 && self.state == ChargeState::Charging {
+                    self.state = ChargeState::Full;
+                    return Some(ChargeEvent::Charged { level });
+                }
+                None
+            }
+        }
+    }
+
+    /// Whether the battery is charging, as far as the levels tell
+    pub fn state(&self) -> ChargeState {
+        self.state
+    }
+
+    /// Add a point to the current discharge segment
+    fn push_point(&mut self, time: SystemTime, level: u8) {
+        if let [.., (_, before_last), (_, last_level)] = self.points[..] {
+            // The last point only marks the latest reading on its step: move
+            // it forward, or drop it for the first reading of the next step
+            if before_last == last_level {
+                self.points.pop();
+            }
+        }
+        self.points.push((time, level));
+
+        // Keep the window bounded
+        if let Ok(age) = time.duration_since(self.points[0].0) {
+            if age > ESTIMATE_WINDOW && self.points.len() > 2 {
+                self.points.remove(0);
+            }
+        }
+    }
+
+    /// Latest level and the time it was seen
+    pub fn latest(&self) -> Option<(SystemTime, u8)> {
+        self.latest
+    }
+
+    /// Estimate from the current segment, or from the previous one while the
+    /// current one is too short; `None` without enough data, while charging
+    /// or if the level is not falling, or too slowly to put a time on
+    pub fn estimate(&self, low_threshold: u8) -> Option<DischargeEstimate> {
+        if matches!(self.state, ChargeState::Charging | ChargeState::Full) {
+            return None;
+        }
+        let (_, level) = self.latest()?;
+        let rate = self.segment_rate().or(self.previous_rate)?;
+        let hours =
+            |points: u8| Duration::try_from_secs_f64(f64::from(points) / rate * 3600.0).ok();
+        let until_low = if level > low_threshold {
+            Some(hours(level - low_threshold)?)
+        } else {
+            None
+        };
+        Some(DischargeEstimate {
+            rate,
+            until_low,
+            until_empty: hours(level)?,
+        })
+    }
+
+    /// Points lost per hour over the current segment, if it is long enough
+    fn segment_rate(&self) -> Option<f64> {
+        let (&(first_time, first_level), &(last_time, last_level)) =
+            (self.points.first()?, self.points.last()?);
+        let span = last_time.duration_since(first_time).ok()?;
+        if span < MIN_SPAN || first_level < last_level.saturating_add(MIN_DROP) {
+            return None;
+        }
+
+        let points: Vec<(f64, f64)> = self
+            .points
+            .iter()
+            .map(|&(time, level)| {
+                let hours = time
+                    .duration_since(first_time)
+                    .unwrap_or_default()
+                    .as_secs_f64()
+                    / 3600.0;
+                (hours, f64::from(level))
+            })
+            .collect();
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+        let (covariance, variance) = points.iter().fold((0.0, 0.0), |(cov, var), (x, y)| {
+            (
+                cov + (x - mean_x) * (y - mean_y),
+                var + (x - mean_x) * (x - mean_x),
+            )
+        });
+        let rate = -covariance / variance;
+        (rate.is_finite() && rate > 0.0).then_some(rate)
+    }
+}
+
+/// Rough remaining time for display, e.g. "~3 days" or "~5 hours"
+pub fn format_remaining(remaining: Duration) -> String {
+    let hours = remaining.as_secs_f64() / 3600.0;
+    if hours < 1.0 {
+        "<1 hour".to_string()
+    } else if hours < 48.0 {
+        let hours = hours.round() as u64;
+        format!("~{hours} hour{}", if hours == 1 { "" } else { "s" })
+    } else {
+        format!("~{} days", (hours / 24.0).round() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    fn at(hours: f64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000) + HOUR.mul_f64(hours)
+    }
+
+    /// Feed `(hours, level)` readings, returning the charge events
+    fn feed(trend: &mut LevelTrend, readings: &[(f64, u8)]) -> Vec<ChargeEvent> {
+        readings
+            .iter()
+            .filter_map(|&(hours, level)| trend.push(at(hours), level))
+            .collect()
+    }
+
+    /// Readings every `poll` hours from `from` until `to`, stepping down one
+    /// point every `step` hours from `level`
+    fn steps(from: f64, to: f64, poll: f64, step: f64, level: u8) -> Vec<(f64, u8)> {
+        let mut readings = Vec::new();
+        let mut hours = from;
+        while hours <= to + 1e-9 {
+            let dropped = ((hours - from) / step + 1e-9).floor() as u8;
+            readings.push((hours, level - dropped));
+            hours += poll;
+        }
+        readings
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{actual} is not {expected}"
+        );
+    }
+
+    fn assert_hours(actual: Duration, hours: f64) {
+        assert_close(actual.as_secs_f64() / 3600.0, hours);
+    }
+
+    #[test]
+    fn rate_of_step_wise_levels() {
+        // 90% down to 86%, one point every five hours, polled every ten
+        // minutes; the last reading is the first one at 86%
+        let mut trend = LevelTrend::default();
+        feed(&mut trend, &steps(0.0, 20.0, 1.0 / 6.0, 5.0, 90));
+        assert_eq!(trend.latest(), Some((at(20.0), 86)));
+
+        let estimate = trend.estimate(20).unwrap();
+        assert_close(estimate.rate, 0.2);
+        assert_hours(estimate.until_empty, 86.0 / 0.2);
+        assert_hours(estimate.until_low.unwrap(), 66.0 / 0.2);
+    }
+
+    #[test]
+    fn polling_density_does_not_skew_the_rate() {
+        // The first plateau is polled every minute, the others hourly
+        let mut readings = steps(0.0, 4.99, 1.0 / 60.0, 5.0, 90);
+        readings.extend(steps(5.0, 20.0, 1.0, 5.0, 89));
+        let mut trend = LevelTrend::default();
+        feed(&mut trend, &readings);
+
+        assert_close(trend.estimate(20).unwrap().rate, 0.2);
+    }
+
+    #[test]
+    fn time_on_the_current_plateau_counts() {
+        // Three hours into the 86% plateau the fit bends slightly towards it
+        let mut trend = LevelTrend::default();
+        feed(&mut trend, &steps(0.0, 23.0, 1.0, 5.0, 90));
+        let rate = trend.estimate(20).unwrap().rate;
+        assert!(rate < 0.2 && rate > 0.17, "{rate}");
+    }
+
+    #[test]
+    fn too_little_data() {
+        let mut trend = LevelTrend::default();
+        assert_eq!(trend.estimate(20), None);
+
+        // A drop of one point is within the reporting granularity
+        feed(&mut trend, &[(0.0, 90), (5.0, 89)]);
+        assert_eq!(trend.estimate(20), None);
+
+        // Two points, but within less than an hour
+        let mut trend = LevelTrend::default();
+        feed(&mut trend, &[(0.0, 90), (0.5, 88)]);
+        assert_eq!(trend.estimate(20), None);
+
+        // A flat level never runs out
+        let mut trend = LevelTrend::default();
+        feed(&mut trend, &steps(0.0, 24.0, 1.0, 100.0, 90));
+        assert_eq!(trend.estimate(20), None);
+    }
+
+    #[test]
+    fn rates_too_slow_for_a_duration() {
+        let trend = LevelTrend {
+            previous_rate: Some(1e-300),
+            state: ChargeState::Discharging,
+            latest: Some((at(0.0), 80)),
+            ..Default::default()
+        };
+        assert_eq!(trend.estimate(20), None);
+    }
+
+    #[test]
+    fn at_or_below_the_threshold() {
+        let mut trend = LevelTrend::default();
+        feed(&mut trend, &steps(0.0, 20.0, 1.0, 5.0, 24));
+        let estimate = trend.estimate(20).unwrap();
+        assert_eq!(estimate.until_low, None);
+        assert_hours(estimate.until_empty, 20.0 / 0.2);
+    }
+
+    #[test]
+    fn old_readings_are_ignored() {
+        let mut trend = LevelTrend::default();
+        feed(&mut trend, &steps(0.0, 10.0, 1.0, 5.0, 90));
+        trend.push(at(5.0), 50);
+        assert_eq!(trend.latest(), Some((at(10.0), 88)));
+        assert_close(trend.estimate(20).unwrap().rate, 0.2);
+    }
+
+    #[test]
+    fn window_drops_the_oldest_points() {
+        // A fast drop first, then one point every two days for 22 days
+        let mut readings = vec![(0.0, 100), (1.0, 96)];
+        readings.extend(steps(2.0, 2.0 + 11.0 * 48.0, 6.0, 48.0, 95));
+        assert!(HOUR * 11 * 48 > ESTIMATE_WINDOW);
+        let mut trend = LevelTrend::default();
+        feed(&mut trend, &readings);
+
+        assert_close(trend.estimate(20).unwrap().rate, 1.0 / 48.0);
+    }
+
+    #[test]
+    fn charging_resets_the_fit() {
+        let mut trend = LevelTrend::default();
+        // 1% per hour from 90% down to 80%
+        feed(&mut trend, &steps(0.0, 10.0, 0.5, 1.0, 90));
+        assert_close(trend.estimate(20).unwrap().rate, 1.0);
+
+        let charge = [(11.0, 82), (11.5, 86), (12.0, 93), (12.5, 100)];
+        let events = feed(&mut trend, &charge);
+        assert_eq!(
+            events,
+            [
+                ChargeEvent::Started { level: 86 },
+                ChargeEvent::Charged { level: 100 }
+            ]
+        );
+        assert_eq!(trend.state(), ChargeState::Full);
+        assert_eq!(trend.estimate(20), None);
+
+        // Unplugged: until the new segment is long enough, the previous
+        // rate stands in
+        let events = feed(&mut trend, &[(13.0, 100), (14.0, 98)]);
+        assert_eq!(events, []);
+        assert_eq!(trend.state(), ChargeState::Discharging);
+        let estimate = trend.estimate(20).unwrap();
+        assert_close(estimate.rate, 1.0);
+        assert_hours(estimate.until_empty, 98.0);
+
+        // Then the new segment's own rate, without the old one mixed in
+        feed(&mut trend, &steps(14.0, 22.0, 0.5, 2.0, 98));
+        assert_close(trend.estimate(20).unwrap().rate, 0.5);
+    }
+
+    #[test]
+    fn jitter_is_not_charging() {
+        for readings in [
+            [(0.0, 78), (1.0, 77), (2.0, 79), (3.0, 77)],
+            // Held, but never rising further
+            [(0.0, 77), (1.0, 79), (2.0, 79), (3.0, 80)],
+            // Rising again only after falling back
+            [(0.0, 77), (1.0, 79), (2.0, 78), (3.0, 80)],
+        ] {
+            let mut trend = LevelTrend::default();
+            assert_eq!(feed(&mut trend, &readings), [], "{readings:?}");
+            assert_ne!(trend.state(), ChargeState::Charging, "{readings:?}");
+        }
+    }
+
+    #[test]
+    fn sustained_rise_is_charging() {
+        let mut trend = LevelTrend::default();
+        let events = feed(
+            &mut trend,
+            &[
+                (0.0, 50),
+                (1.0, 48),
+                (1.1, 50),
+                (1.2, 50),
+                (1.3, 51),
+                (1.4, 52),
+            ],
+        );
+        assert_eq!(events, [ChargeEvent::Started { level: 52 }]);
+        assert_eq!(trend.state(), ChargeState::Charging);
+
+        // Unplugged at 90%
+        let events = feed(&mut trend, &[(2.0, 90), (2.5, 89), (3.0, 88)]);
+        assert_eq!(events, [ChargeEvent::Charged { level: 90 }]);
+        assert_eq!(trend.state(), ChargeState::Discharging);
+    }
+
+    #[test]
+    fn topping_up_to_full() {
+        let mut trend = LevelTrend::default();
+        let events = feed(&mut trend, &[(0.0, 97), (1.0, 99), (1.5, 100)]);
+        assert_eq!(events, [ChargeEvent::Started { level: 100 }]);
+        assert_eq!(trend.state(), ChargeState::Full);
+
+        // Not charged to anything: it was full as soon as it was noticed
+        assert_eq!(feed(&mut trend, &[(5.0, 98)]), []);
+    }
+
+    #[test]
+    fn from_samples_matches_push() {
+        let samples: Vec<_> = steps(0.0, 20.0, 1.0, 5.0, 90)
+            .into_iter()
+            .map(|(hours, level)| Sample {
+                time: at(hours),
+                device: "Corne".to_string(),
+                address: None,
+                battery: "Central".to_string(),
+                level,
+            })
+            .collect();
+        let trend = LevelTrend::from_samples(&samples);
+        assert_close(trend.estimate(20).unwrap().rate, 0.2);
+    }
+
+    #[test]
+    fn remaining_time_text() {
+        for (hours, text) in [
+            (0.0, "<1 hour"),
+            (0.9, "<1 hour"),
+            (1.0, "~1 hour"),
+            (1.4, "~1 hour"),
+            (1.6, "~2 hours"),
+            (5.0, "~5 hours"),
+            (47.0, "~47 hours"),
+            (48.0, "~2 days"),
+            (80.0, "~3 days"),
+            (24.0 * 30.0, "~30 days"),
+        ] {
+            assert_eq!(format_remaining(HOUR.mul_f64(hours)), text, "{hours} hours");
+        }
+    }
+}
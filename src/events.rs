@@ -0,0 +1,477 @@
+use futures_util::stream::{self, BoxStream, StreamExt};
+use std::collections::HashMap;
+use std::future::Future;
+use zbus::message::Type;
+use zbus::zvariant::{OwnedObjectPath, OwnedValue};
+use zbus::{MatchRule, Message, MessageStream};
+
+use crate::{BdAddr, Result, ZmkBatteryReader, BATTERY_UUID};
+
+/// Change in the connection state of a Bluetooth device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    Connected {
+        address: BdAddr,
+    },
+    Disconnected {
+        address: BdAddr,
+    },
+    /// GATT services were discovered and can be read
+    ServicesResolved {
+        address: BdAddr,
+    },
+    BatteryServiceAppeared {
+        address: BdAddr,
+    },
+    /// The device was removed from BlueZ, e.g. unpaired
+    Removed {
+        address: BdAddr,
+    },
+}
+
+impl DeviceEvent {
+    pub fn address(&self) -> BdAddr {
+        match *self {
+            Self::Connected { address }
+            | Self::Disconnected { address }
+            | Self::ServicesResolved { address }
+            | Self::BatteryServiceAppeared { address }
+            | Self::Removed { address } => address,
+        }
+    }
+}
+
+/// Stream of device events from BlueZ
+pub type DeviceEventStream = BoxStream<'static, DeviceEvent>;
+
+/// Whether BlueZ is running on the system bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendState {
+    Available,
+    /// `bluetoothd` is not running, e.g. while it restarts
+    Unavailable,
+}
+
+/// Stream of BlueZ availability changes
+pub type BackendStateStream = BoxStream<'static, BackendState>;
+
+type InterfacesAdded = (
+    OwnedObjectPath,
+    HashMap<String, HashMap<String, OwnedValue>>,
+);
+type PropertiesChanged = (String, HashMap<String, OwnedValue>, Vec<String>);
+
+impl ZmkBatteryReader {
+    /// Watch all devices for connects, disconnects and newly resolved
+    /// services.
+    ///
+    /// Built from `InterfacesAdded`/`InterfacesRemoved` on BlueZ's object
+    /// manager and `PropertiesChanged` on `org.bluez.Device1`.
+    pub async fn device_events(&self) -> Result<DeviceEventStream> {
+        let object_manager = zbus::Proxy::new(
+            &self.conn,
+            "org.bluez",
+            "/",
+            "org.freedesktop.DBus.ObjectManager",
+        )
+        .await?;
+        let added = object_manager
+            .receive_signal("InterfacesAdded")
+            .await?
+            .map(|message| parse_interfaces_added(&message));
+        let removed = object_manager
+            .receive_signal("InterfacesRemoved")
+            .await?
+            .map(|message| parse_interfaces_removed(&message));
+
+        let rule = MatchRule::builder()
+            .msg_type(Type::Signal)
+            .sender("org.bluez")?
+            .interface("org.freedesktop.DBus.Properties")?
+            .member("PropertiesChanged")?
+            .path_namespace("/org/bluez")?
+            .arg(0, "org.bluez.Device1")?
+            .build();
+        let changed = MessageStream::for_match_rule(rule, &self.conn, None)
+            .await?
+            .map(|message| match message {
+                Ok(message) => parse_properties_changed(&message),
+                Err(_) => Vec::new(),
+            });
+
+        Ok(
+            stream::select_all([added.boxed(), removed.boxed(), changed.boxed()])
+                .flat_map(stream::iter)
+                .boxed(),
+        )
+    }
+
+    /// Watch BlueZ coming and going, e.g. on `systemctl restart bluetooth`.
+    ///
+    /// Yields the current state first, then every change of the owner of
+    /// `org.bluez`. Notification subscriptions do not survive a restart:
+    /// their streams end and have to be set up again once BlueZ is back.
+    /// The reader itself keeps no BlueZ state, so reads work again as soon
+    /// as `Available` is reported.
+    pub async fn backend_states(&self) -> Result<BackendStateStream> {
+        // Subscribe before asking, so that no change can slip in between
+        let changes = self.name_owner_changes().await?;
+
+        let dbus = zbus::Proxy::new(
+            &self.conn,
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+        )
+        .await?;
+        let has_owner: bool = dbus
+            .call_method("NameHasOwner", &("org.bluez",))
+            .await?
+            .body()
+            .deserialize()?;
+        let current = if has_owner {
+            BackendState::Available
+        } else {
+            BackendState::Unavailable
+        };
+
+        let changes = changes
+            .flat_map(|(old_owner, new_owner)| stream::iter(owner_change(&old_owner, &new_owner)));
+
+        Ok(stream::once(async move { current }).chain(changes).boxed())
+    }
+
+    /// Resolves once the current BlueZ instance goes away
+    pub(crate) async fn backend_lost(&self) -> Result<impl Future<Output = ()> + Send + 'static> {
+        let mut changes = self.name_owner_changes().await?;
+        Ok(async move {
+            while let Some((old_owner, _)) = changes.next().await {
+                if !old_owner.is_empty() {
+                    return;
+                }
+            }
+        })
+    }
+
+    /// `(old_owner, new_owner)` for every `NameOwnerChanged` of `org.bluez`
+    async fn name_owner_changes(&self) -> Result<BoxStream<'static, (String, String)>> {
+        let rule = MatchRule::builder()
+            .msg_type(Type::Signal)
+            .sender("org.freedesktop.DBus")?
+            .interface("org.freedesktop.DBus")?
+            .member("NameOwnerChanged")?
+            .arg(0, "org.bluez")?
+            .build();
+        let changes = MessageStream::for_match_rule(rule, &self.conn, None)
+            .await?
+            .filter_map(|message| async move { parse_name_owner_changed(&message.ok()?) });
+        Ok(changes.boxed())
+    }
+}
+
+fn parse_interfaces_added(message: &Message) -> Vec<DeviceEvent> {
+    let Ok((path, interfaces)) = message.body().deserialize::<InterfacesAdded>() else {
+        return Vec::new();
+    };
+    let Some(address) = BdAddr::from_object_path(path.as_str()) else {
+        return Vec::new();
+    };
+
+    let mut events = Vec::new();
+    if let Some(props) = interfaces.get("org.bluez.Device1") {
+        if flag(props, "Connected") {
+            events.push(DeviceEvent::Connected { address });
+        }
+        if flag(props, "ServicesResolved") {
+            events.push(DeviceEvent::ServicesResolved { address });
+        }
+    }
+    if let Some(props) = interfaces.get("org.bluez.GattService1") {
+        let uuid = props
+            .get("UUID")
+            .and_then(|v| String::try_from(v.try_clone().ok()?).ok());
+        if uuid.as_deref() == Some(BATTERY_UUID) {
+            events.push(DeviceEvent::BatteryServiceAppeared { address });
+        }
+    }
+    events
+}
+
+fn parse_interfaces_removed(message: &Message) -> Vec<DeviceEvent> {
+    let Ok((path, interfaces)) = message
+        .body()
+        .deserialize::<(OwnedObjectPath, Vec<String>)>()
+    else {
+        return Vec::new();
+    };
+
+    match BdAddr::from_object_path(path.as_str()) {
+        Some(address) if interfaces.iter().any(|i| i == "org.bluez.Device1") => {
+            vec![DeviceEvent::Removed { address }]
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn parse_properties_changed(message: &Message) -> Vec<DeviceEvent> {
+    let header = message.header();
+    let Some(address) = header
+        .path()
+        .and_then(|p| BdAddr::from_object_path(p.as_str()))
+    else {
+        return Vec::new();
+    };
+    let Ok((interface, changed, _invalidated)) = message.body().deserialize::<PropertiesChanged>()
+    else {
+        return Vec::new();
+    };
+    if interface != "org.bluez.Device1" {
+        return Vec::new();
+    }
+
+    let mut events = Vec::new();
+    if changed.contains_key("Connected") {
+        events.push(if flag(&changed, "Connected") {
+            DeviceEvent::Connected { address }
+        } else {
+            DeviceEvent::Disconnected { address }
+        });
+    }
+    if flag(&changed, "ServicesResolved") {
+        events.push(DeviceEvent::ServicesResolved { address });
+    }
+    events
+}
+
+/// `(old_owner, new_owner)` of a `NameOwnerChanged` signal
+fn parse_name_owner_changed(message: &Message) -> Option<(String, String)> {
+    let (_name, old_owner, new_owner) = message
+        .body()
+        .deserialize::<(String, String, String)>()
+        .ok()?;
+    Some((old_owner, new_owner))
+}
+
+/// States BlueZ went through when its name changed owner
+fn owner_change(old_owner: &str, new_owner: &str) -> Vec<BackendState> {
+    // A direct handover still invalidates everything of the old owner
+    let mut states = Vec::new();
+    if !old_owner.is_empty() {
+        states.push(BackendState::Unavailable);
+    }
+    if !new_owner.is_empty() {
+        states.push(BackendState::Available);
+    }
+    states
+}
+
+fn flag(props: &HashMap<String, OwnedValue>, name: &str) -> bool {
+    props
+        .get(name)
+        .and_then(|v| bool::try_from(v).ok())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zbus::zvariant::{ObjectPath, Value};
+
+    const PATH: &str = "/org/bluez/hci0/dev_AA_BB_CC_DD_EE_01";
+
+    fn address() -> BdAddr {
+        "AA:BB:CC:DD:EE:01".parse().unwrap()
+    }
+
+    fn properties_changed(path: &str, interface: &str, changed: &[(&str, bool)]) -> Message {
+        let changed: HashMap<&str, Value> = changed
+            .iter()
+            .map(|&(name, value)| (name, Value::from(value)))
+            .collect();
+        Message::signal(path, "org.freedesktop.DBus.Properties", "PropertiesChanged")
+            .unwrap()
+            .build(&(interface, changed, Vec::<&str>::new()))
+            .unwrap()
+    }
+
+    fn interfaces_added(path: &str, interfaces: HashMap<&str, HashMap<&str, Value>>) -> Message {
+        Message::signal("/", "org.freedesktop.DBus.ObjectManager", "InterfacesAdded")
+            .unwrap()
+            .build(&(ObjectPath::try_from(path).unwrap(), interfaces))
+            .unwrap()
+    }
+
+    fn device_props(
+        connected: bool,
+        services_resolved: bool,
+    ) -> HashMap<&'static str, Value<'static>> {
+        HashMap::from([
+            ("Address", Value::from("AA:BB:CC:DD:EE:01")),
+            ("Connected", Value::from(connected)),
+            ("ServicesResolved", Value::from(services_resolved)),
+        ])
+    }
+
+    fn gatt_service(uuid: &str) -> HashMap<&str, Value<'_>> {
+        HashMap::from([("UUID", Value::from(uuid))])
+    }
+
+    #[test]
+    fn connected_and_disconnected() {
+        let address = address();
+        let message = properties_changed(PATH, "org.bluez.Device1", &[("Connected", true)]);
+        assert_eq!(
+            parse_properties_changed(&message),
+            [DeviceEvent::Connected { address }]
+        );
+
+        let message = properties_changed(PATH, "org.bluez.Device1", &[("Connected", false)]);
+        assert_eq!(
+            parse_properties_changed(&message),
+            [DeviceEvent::Disconnected { address }]
+        );
+    }
+
+    #[test]
+    fn services_resolved_is_not_connected() {
+        let address = address();
+        let message = properties_changed(PATH, "org.bluez.Device1", &[("ServicesResolved", true)]);
+        assert_eq!(
+            parse_properties_changed(&message),
+            [DeviceEvent::ServicesResolved { address }]
+        );
+
+        // Services going away is not an event of its own
+        let message = properties_changed(PATH, "org.bluez.Device1", &[("ServicesResolved", false)]);
+        assert_eq!(parse_properties_changed(&message), []);
+
+        let message = properties_changed(
+            PATH,
+            "org.bluez.Device1",
+            &[("Connected", true), ("ServicesResolved", true)],
+        );
+        assert_eq!(
+            parse_properties_changed(&message),
+            [
+                DeviceEvent::Connected { address },
+                DeviceEvent::ServicesResolved { address }
+            ]
+        );
+    }
+
+    #[test]
+    fn other_properties_and_objects_are_ignored() {
+        let message = properties_changed(PATH, "org.bluez.Device1", &[("Trusted", true)]);
+        assert_eq!(parse_properties_changed(&message), []);
+
+        let message = properties_changed(PATH, "org.bluez.MediaControl1", &[("Connected", true)]);
+        assert_eq!(parse_properties_changed(&message), []);
+
+        let message = properties_changed(
+            "/org/bluez/hci0",
+            "org.bluez.Device1",
+            &[("Connected", true)],
+        );
+        assert_eq!(parse_properties_changed(&message), []);
+    }
+
+    #[test]
+    fn new_device() {
+        let address = address();
+        let message = interfaces_added(
+            PATH,
+            HashMap::from([("org.bluez.Device1", device_props(true, true))]),
+        );
+        assert_eq!(
+            parse_interfaces_added(&message),
+            [
+                DeviceEvent::Connected { address },
+                DeviceEvent::ServicesResolved { address }
+            ]
+        );
+
+        // Paired but asleep
+        let message = interfaces_added(
+            PATH,
+            HashMap::from([("org.bluez.Device1", device_props(false, false))]),
+        );
+        assert_eq!(parse_interfaces_added(&message), []);
+    }
+
+    #[test]
+    fn battery_service_appearing() {
+        let message = interfaces_added(
+            &format!("{PATH}/service0010"),
+            HashMap::from([("org.bluez.GattService1", gatt_service(BATTERY_UUID))]),
+        );
+        assert_eq!(
+            parse_interfaces_added(&message),
+            [DeviceEvent::BatteryServiceAppeared { address: address() }]
+        );
+
+        // Device Information is not a battery
+        let message = interfaces_added(
+            &format!("{PATH}/service0020"),
+            HashMap::from([(
+                "org.bluez.GattService1",
+                gatt_service("0000180a-0000-1000-8000-00805f9b34fb"),
+            )]),
+        );
+        assert_eq!(parse_interfaces_added(&message), []);
+
+        // Neither are characteristics with the same UUID
+        let message = interfaces_added(
+            &format!("{PATH}/service0010/char0011"),
+            HashMap::from([("org.bluez.GattCharacteristic1", gatt_service(BATTERY_UUID))]),
+        );
+        assert_eq!(parse_interfaces_added(&message), []);
+    }
+
+    #[test]
+    fn removed_device() {
+        let removed = |path: &str, interfaces: &[&str]| {
+            let message = Message::signal(
+                "/",
+                "org.freedesktop.DBus.ObjectManager",
+                "InterfacesRemoved",
+            )
+            .unwrap()
+            .build(&(ObjectPath::try_from(path).unwrap(), interfaces))
+            .unwrap();
+            parse_interfaces_removed(&message)
+        };
+        assert_eq!(
+            removed(
+                PATH,
+                &["org.freedesktop.DBus.Properties", "org.bluez.Device1"]
+            ),
+            [DeviceEvent::Removed { address: address() }]
+        );
+        assert_eq!(
+            removed(&format!("{PATH}/service0010"), &["org.bluez.GattService1"]),
+            []
+        );
+    }
+
+    #[test]
+    fn bluez_restarts() {
+        let changed = |old: &str, new: &str| {
+            let message = Message::signal(
+                "/org/freedesktop/DBus",
+                "org.freedesktop.DBus",
+                "NameOwnerChanged",
+            )
+            .unwrap()
+            .build(&("org.bluez", old, new))
+            .unwrap();
+            let (old, new) = parse_name_owner_changed(&message).unwrap();
+            owner_change(&old, &new)
+        };
+        assert_eq!(changed(":1.5", ""), [BackendState::Unavailable]);
+        assert_eq!(changed("", ":1.9"), [BackendState::Available]);
+        assert_eq!(
+            changed(":1.5", ":1.9"),
+            [BackendState::Unavailable, BackendState::Available]
+        );
+    }
+}
@@ -0,0 +1,54 @@
+//! Battery status as text, as shown in the tray tooltip.
+
+use std::collections::BTreeMap;
+
+use crate::config::TierConfig;
+use crate::estimate::format_remaining;
+use crate::{ChargeState, DeviceStatus, ZmkError};
+
+/// Tooltip text of one device: its batteries, or why they are unknown,
+/// followed by its details
+pub fn format_device_state(state: &DeviceStatus, tiers: &BTreeMap<String, TierConfig>) -> String {
+    let mut text = match &state.error {
+        Some(ZmkError::Disconnected(_)) => "Asleep (disconnected)".to_string(),
+        Some(e) => format!("Error: {e}"),
+        None => format_battery_info(state, tiers),
+    };
+    if state.reconnected {
+        text.push_str("\nReconnected");
+    }
+    if let Some(details) = state.details.as_ref().filter(|d| !d.is_empty()) {
+        text.push('\n');
+        text.push_str(&details.to_string());
+    }
+    text
+}
+
+/// One line per battery with its level, charge state or time left and the
+/// tier it is in
+pub fn format_battery_info(state: &DeviceStatus, tiers: &BTreeMap<String, TierConfig>) -> String {
+    if state.batteries.is_empty() {
+        "No battery data available".to_string()
+    } else {
+        state
+            .batteries
+            .iter()
+            .map(|b| {
+                let warning = state
+                    .device
+                    .tier(b, tiers)
+                    .map(|tier| format!(" ⚠ {}", tier.label))
+                    .unwrap_or_default();
+                let remaining = match b.charging {
+                    ChargeState::Charging | ChargeState::Full => format!(" — {}", b.charging),
+                    _ => state
+                        .estimate(b, tiers)
+                        .map(|e| format!(" — {} left", format_remaining(e.until_empty)))
+                        .unwrap_or_default(),
+                };
+                format!("{}: {}%{}{}", b.name, b.level, remaining, warning)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
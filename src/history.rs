@@ -0,0 +1,375 @@
+//! Battery history, kept as an append-only JSON Lines file.
+//!
+//! Every line is one `Sample`: the level of one battery of one device at one
+//! point in time. Writers only ever append whole lines, so the tray and CLI
+//! can record into the same file. Samples older than the retention period
+//! are pruned when the history is opened and then once a day.
+//!
+//! Pruning rewrites the file, so appends and prunes hold an advisory lock on
+//! a `.lock` file next to it; otherwise lines appended by another process
+//! while the file is rewritten would be lost. Readers need no lock as the
+//! file is replaced in one step.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::{DeviceConfig, HistoryConfig};
+use crate::{BatteryInfo, BdAddr};
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Level of one battery at one point in time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sample {
+    /// Stored as Unix time in seconds
+    #[serde(with = "unix_seconds")]
+    pub time: SystemTime,
+    /// Device name from the config
+    pub device: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<BdAddr>,
+    /// Battery name after `battery_names` was applied
+    pub battery: String,
+    pub level: u8,
+}
+
+impl Sample {
+    pub fn new(
+        device: &DeviceConfig,
+        address: Option<BdAddr>,
+        battery: &BatteryInfo,
+        time: SystemTime,
+    ) -> Self {
+        Self {
+            time,
+            device: device.name.clone(),
+            address,
+            battery: battery.name.clone(),
+            level: battery.level,
+        }
+    }
+}
+
+pub struct History {
+    path: PathBuf,
+    /// Samples older than this are pruned; kept forever if `None`
+    retention: Option<Duration>,
+    last_prune: Option<Instant>,
+}
+
+impl History {
+    /// Open the history configured in `[history]`, pruning old samples
+    pub fn open(config: &HistoryConfig) -> Result<Self> {
+        let retention = (config.retention_days > 0)
+            .then(|| Duration::from_secs(u64::from(config.retention_days) * 24 * 60 * 60));
+        let mut history = Self::at(config.path()?, retention);
+        history.prune()?;
+        Ok(history)
+    }
+
+    /// Like `open`, but `None` if the history is disabled
+    pub fn open_enabled(config: &HistoryConfig) -> Result<Option<Self>> {
+        config.enabled.then(|| Self::open(config)).transpose()
+    }
+
+    /// History stored in `path`; nothing is read or written until used
+    pub fn at(path: impl Into<PathBuf>, retention: Option<Duration>) -> Self {
+        Self {
+            path: path.into(),
+            retention,
+            last_prune: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append samples, pruning first if the last prune was a day ago
+    pub fn record(&mut self, samples: &[Sample]) -> Result<()> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        if self
+            .last_prune
+            .is_some_and(|last| last.elapsed() >= PRUNE_INTERVAL)
+        {
+            self.prune()?;
+        }
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).with_context(|| {
+                format!("Failed to create history directory: {}", parent.display())
+            })?;
+        }
+        // One write per call so that concurrent writers never interleave lines
+        let mut lines = String::new();
+        for sample in samples {
+            lines.push_str(&serde_json::to_string(sample)?);
+            lines.push('\n');
+        }
+        let _lock = self.lock()?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(lines.as_bytes()))
+            .with_context(|| format!("Failed to write history file: {}", self.path.display()))
+    }
+
+    /// Record the levels of one device read at `time`
+    pub fn record_batteries(
+        &mut self,
+        device: &DeviceConfig,
+        address: Option<BdAddr>,
+        batteries: &[BatteryInfo],
+        time: SystemTime,
+    ) -> Result<()> {
+        let samples: Vec<_> = batteries
+            .iter()
+            .map(|battery| Sample::new(device, address, battery, time))
+            .collect();
+        self.record(&samples)
+    }
+
+    /// Samples taken within `range`, of device `device` or of every device,
+    /// oldest first
+    pub fn query(
+        &self,
+        range: impl RangeBounds<SystemTime>,
+        device: Option<&str>,
+    ) -> Result<Vec<Sample>> {
+        let mut samples: Vec<_> = self
+            .samples()?
+            .into_iter()
+            .filter(|sample| range.contains(&sample.time))
+            .filter(|sample| device.is_none_or(|name| sample.device == name))
+            .collect();
+        samples.sort_by_key(|sample| sample.time);
+        Ok(samples)
+    }
+
+    /// Drop samples older than the retention period, returning how many
+    pub fn prune(&mut self) -> Result<usize> {
+        self.last_prune = Some(Instant::now());
+        let Some(retention) = self.retention else {
+            return Ok(0);
+        };
+        let cutoff = SystemTime::now()
+            .checked_sub(retention)
+            .unwrap_or(UNIX_EPOCH);
+        if !self.path.exists() {
+            return Ok(0);
+        }
+
+        // Nothing may be appended between reading the file and replacing it.
+        // A failed read leaves the file alone, as whatever could not be read
+        // would be lost
+        let _lock = self.lock()?;
+        let mut kept = Vec::new();
+        let mut pruned = 0;
+        for sample in self.samples()? {
+            if sample.time >= cutoff {
+                kept.push(sample);
+            } else {
+                pruned += 1;
+            }
+        }
+        if pruned == 0 {
+            return Ok(0);
+        }
+
+        // Replace the file in one step so readers never see half of it
+        let tmp = self
+            .path
+            .with_extension(format!("jsonl.{}.tmp", std::process::id()));
+        let write = || -> Result<()> {
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            for sample in &kept {
+                serde_json::to_writer(&mut writer, sample)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+            fs::rename(&tmp, &self.path)?;
+            Ok(())
+        };
+        write()
+            .with_context(|| format!("Failed to prune history file: {}", self.path.display()))?;
+        Ok(pruned)
+    }
+
+    /// Take the lock that appends and prunes hold; it is released when the
+    /// returned file is dropped
+    fn lock(&self) -> Result<File> {
+        let path = self.path.with_extension("jsonl.lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open history lock: {}", path.display()))?;
+        file.lock()
+            .with_context(|| format!("Failed to lock history: {}", path.display()))?;
+        Ok(file)
+    }
+
+    /// Every readable sample in file order; lines that fail to parse, e.g.
+    /// one cut short by a crash, are skipped. Fails if the file cannot be
+    /// read to the end.
+    fn samples(&self) -> Result<Vec<Sample>> {
+        let context = || format!("Failed to read history file: {}", self.path.display());
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(context),
+        };
+        // Split on raw bytes: a line need not even be valid UTF-8
+        BufReader::new(file)
+            .split(b'\n')
+            .filter_map(|line| match line {
+                Ok(line) => serde_json::from_slice(&line).ok().map(Ok),
+                Err(e) => Some(Err(e)),
+            })
+            .collect::<io::Result<_>>()
+            .with_context(context)
+    }
+}
+
+mod unix_seconds {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        let seconds = time
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        serializer.serialize_u64(seconds)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        let seconds = u64::deserialize(deserializer)?;
+        Ok(UNIX_EPOCH + Duration::from_secs(seconds))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn path(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "zmk-battery-monitor-history-{}-{test}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("history.jsonl")
+    }
+
+    fn sample(time: SystemTime, level: u8) -> Sample {
+        Sample {
+            time,
+            device: "Corne".to_string(),
+            address: None,
+            battery: "Central".to_string(),
+            level,
+        }
+    }
+
+    #[test]
+    fn records_and_queries() {
+        let path = path("query");
+        let mut history = History::at(&path, None);
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut other = sample(now, 50);
+        other.device = "Lily58".to_string();
+        history
+            .record(&[sample(now, 80), sample(now - DAY, 90), other])
+            .unwrap();
+
+        let levels = |samples: Vec<Sample>| samples.iter().map(|s| s.level).collect::<Vec<_>>();
+        assert_eq!(levels(history.query(.., None).unwrap()), [90, 80, 50]);
+        assert_eq!(levels(history.query(.., Some("Corne")).unwrap()), [90, 80]);
+        assert_eq!(levels(history.query(now.., Some("Corne")).unwrap()), [80]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn broken_lines_are_skipped() {
+        let path = path("broken");
+        let mut history = History::at(&path, Some(30 * DAY));
+        let now = SystemTime::now();
+        history.record(&[sample(now - 2 * DAY, 90)]).unwrap();
+        // A non-ASCII name cut short in the middle of a character, and a
+        // line cut short elsewhere
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"time\":1,\"device\":\"Corne \xF0\x9F\n")
+            .unwrap();
+        file.write_all(b"{\"time\":1,\"dev\n").unwrap();
+        history
+            .record(&[sample(now - 40 * DAY, 50), sample(now - DAY, 80)])
+            .unwrap();
+
+        let levels = |history: &History| {
+            let samples = history.query(.., None).unwrap();
+            samples.iter().map(|s| s.level).collect::<Vec<_>>()
+        };
+        assert_eq!(levels(&history), [50, 90, 80]);
+        // Pruning drops the old sample and the broken lines, nothing else
+        assert_eq!(history.prune().unwrap(), 1);
+        assert_eq!(levels(&history), [90, 80]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn prunes_old_samples() {
+        let path = path("prune");
+        let mut history = History::at(&path, Some(DAY * 30));
+        assert_eq!(history.prune().unwrap(), 0);
+
+        let now = SystemTime::now();
+        history
+            .record(&[sample(now - DAY * 40, 90), sample(now - DAY, 80)])
+            .unwrap();
+        assert_eq!(history.prune().unwrap(), 1);
+        let kept = history.query(.., None).unwrap();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].level, 80);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn appends_during_prune_are_kept() {
+        let path = path("concurrent");
+        let now = SystemTime::now();
+        let mut history = History::at(&path, Some(DAY));
+        history.record(&[sample(now - DAY * 2, 90)]).unwrap();
+
+        let writer = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let mut history = History::at(path, None);
+                for i in 0..2000 {
+                    history.record(&[sample(now, (i % 100) as u8)]).unwrap();
+                }
+            })
+        };
+        // Keep rewriting the file for as long as the other writer appends
+        while !writer.is_finished() {
+            history.record(&[sample(now - DAY * 2, 90)]).unwrap();
+            history.prune().unwrap();
+        }
+        writer.join().unwrap();
+        history.prune().unwrap();
+
+        assert_eq!(history.query(.., None).unwrap().len(), 2000);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
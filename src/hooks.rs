@@ -0,0 +1,482 @@
+//! User commands run on battery events, configured in `[[hooks]]`.
+//!
+//! Each command runs with `sh -c` and gets the details of the event in
+//! environment variables. Commands run in the background so that a slow one
+//! never holds up monitoring; at most `max_concurrent_hooks` run at once and
+//! each is killed after its `timeout_ms`. Failures are reported on stderr.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+
+use crate::config::{HookConfig, NotificationConfig, TierConfig};
+use crate::notifications::{Alert, LowBatteryTracker};
+use crate::{ChargeEvent, Config, DeviceEvent, DeviceStatus, MonitorEvent};
+
+/// Event a hook can run on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookEvent {
+    /// A battery dropped into a threshold tier
+    Threshold,
+    /// A battery climbed back out of every tier
+    Recovered,
+    Connected,
+    Disconnected,
+    ChargingStarted,
+    Charged,
+    /// Reading a device failed after the previous read succeeded
+    ReadError,
+}
+
+impl fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Threshold => "threshold",
+            Self::Recovered => "recovered",
+            Self::Connected => "connected",
+            Self::Disconnected => "disconnected",
+            Self::ChargingStarted => "charging-started",
+            Self::Charged => "charged",
+            Self::ReadError => "read-error",
+        })
+    }
+}
+
+/// One occurrence of a hook event, with what is known about it
+struct Occurrence {
+    event: HookEvent,
+    device: usize,
+    battery: Option<String>,
+    level: Option<u8>,
+    tier: Option<String>,
+    error: Option<String>,
+}
+
+impl Occurrence {
+    fn new(event: HookEvent, device: usize) -> Self {
+        Self {
+            event,
+            device,
+            battery: None,
+            level: None,
+            tier: None,
+            error: None,
+        }
+    }
+}
+
+/// Runs the configured hooks for the events of a `Monitor`
+pub struct HookRunner {
+    hooks: Vec<HookConfig>,
+    tiers: BTreeMap<String, TierConfig>,
+    limit: Arc<Semaphore>,
+    /// Threshold crossings, with the hysteresis of `[notifications]` but
+    /// without repeats
+    tracker: LowBatteryTracker,
+    crossing: NotificationConfig,
+    /// Whether the last read of each device failed; devices that were not
+    /// read yet are missing
+    failing: HashMap<usize, bool>,
+}
+
+impl HookRunner {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            hooks: config.hooks.clone(),
+            tiers: config.tiers.clone(),
+            limit: Arc::new(Semaphore::new(config.general.max_concurrent_hooks.max(1))),
+            tracker: LowBatteryTracker::default(),
+            crossing: NotificationConfig {
+                repeat_interval_minutes: 0,
+                notify_recovered: true,
+                ..config.notifications.clone()
+            },
+            failing: HashMap::new(),
+        }
+    }
+
+    /// Start the hooks `event` calls for; `devices` is the monitor's device
+    /// status
+    pub fn handle(&mut self, event: &MonitorEvent, devices: &Mutex<Vec<DeviceStatus>>) {
+        if self.hooks.is_empty() {
+            return;
+        }
+        let devices = devices.lock().unwrap();
+        for occurrence in self.occurrences(event, &devices) {
+            let state = &devices[occurrence.device];
+            let env = environment(&occurrence, state);
+            for hook in &self.hooks {
+                let wanted = (hook.events.is_empty() || hook.events.contains(&occurrence.event))
+                    && (hook.devices.is_empty() || hook.devices.contains(&state.device.name));
+                if wanted {
+                    self.spawn(hook, env.clone());
+                }
+            }
+        }
+    }
+
+    fn occurrences(&mut self, event: &MonitorEvent, devices: &[DeviceStatus]) -> Vec<Occurrence> {
+        let mut occurrences = Vec::new();
+        match event {
+            MonitorEvent::LevelChanged {
+                device, battery, ..
+            } => {
+                let state = &devices[*device];
+                let Some(battery) = state.batteries.iter().find(|b| b.name == battery.name) else {
+                    return occurrences;
+                };
+                let tiers = state.device.tiers(battery, &self.tiers);
+                let alert =
+                    self.tracker
+                        .update(&self.crossing, *device, battery, &tiers, Instant::now());
+                let (event, tier) = match alert {
+                    Some(Alert::Low { tier, .. }) => (HookEvent::Threshold, Some(tier.name)),
+                    Some(Alert::Recovered { .. }) => (HookEvent::Recovered, None),
+                    None => return occurrences,
+                };
+                occurrences.push(Occurrence {
+                    battery: Some(battery.name.clone()),
+                    level: Some(battery.level),
+                    tier,
+                    ..Occurrence::new(event, *device)
+                });
+            }
+            MonitorEvent::Refreshed => {
+                for (i, state) in devices.iter().enumerate() {
+                    let failed = state.error.is_some();
+                    // A device asleep since before the start never failed
+                    // after a good read
+                    if failed && self.failing.get(&i) == Some(&false) {
+                        occurrences.push(Occurrence {
+                            error: state.error.as_ref().map(|e| e.to_string()),
+                            ..Occurrence::new(HookEvent::ReadError, i)
+                        });
+                    }
+                    self.failing.insert(i, failed);
+                }
+            }
+            MonitorEvent::Charge {
+                device,
+                battery,
+                event,
+            } => {
+                let (event, level) = match *event {
+                    ChargeEvent::Started { level } => (HookEvent::ChargingStarted, level),
+                    ChargeEvent::Charged { level } => (HookEvent::Charged, level),
+                };
+                occurrences.push(Occurrence {
+                    battery: Some(battery.clone()),
+                    level: Some(level),
+                    ..Occurrence::new(event, *device)
+                });
+            }
+            MonitorEvent::Device { device, event } => {
+                let event = match event {
+                    DeviceEvent::Connected { .. } => HookEvent::Connected,
+                    DeviceEvent::Disconnected { .. } => HookEvent::Disconnected,
+                    _ => return occurrences,
+                };
+                occurrences.push(Occurrence::new(event, *device));
+            }
+            MonitorEvent::Backend(_) | MonitorEvent::HistoryFailed(_) => {}
+        }
+        occurrences
+    }
+
+    fn spawn(&self, hook: &HookConfig, env: Vec<(&'static str, String)>) {
+        let limit = Arc::clone(&self.limit);
+        let command = hook.command.clone();
+        let timeout = Duration::from_millis(hook.timeout_ms);
+        tokio::spawn(async move {
+            let Ok(_permit) = limit.acquire_owned().await else {
+                return;
+            };
+            let child = Command::new("sh")
+                .arg("-c")
+                .arg(&command)
+                .envs(env)
+                .kill_on_drop(true)
+                .spawn();
+            let mut child = match child {
+                Ok(child) => child,
+                Err(e) => {
+                    eprintln!("Failed to run hook {command:?}: {e}");
+                    return;
+                }
+            };
+            match tokio::time::timeout(timeout, child.wait()).await {
+                Ok(Ok(status)) if status.success() => {}
+                Ok(Ok(status)) => eprintln!("Hook {command:?} failed: {status}"),
+                Ok(Err(e)) => eprintln!("Failed to run hook {command:?}: {e}"),
+                Err(_) => {
+                    eprintln!("Hook {command:?} timed out after {timeout:?}, killing it");
+                    let _ = child.kill().await;
+                }
+            }
+        });
+    }
+}
+
+/// Environment variables describing `occurrence`; unknown details are left
+/// out
+fn environment(occurrence: &Occurrence, state: &DeviceStatus) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("ZMK_EVENT", occurrence.event.to_string()),
+        ("ZMK_DEVICE", state.device.name.clone()),
+    ];
+    let optional = [
+        ("ZMK_ADDRESS", state.address.map(|a| a.to_string())),
+        ("ZMK_BATTERY", occurrence.battery.clone()),
+        ("ZMK_LEVEL", occurrence.level.map(|l| l.to_string())),
+        ("ZMK_TIER", occurrence.tier.clone()),
+        ("ZMK_ERROR", occurrence.error.clone()),
+    ];
+    env.extend(
+        optional
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, value?))),
+    );
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BatteryInfo, BatterySource, BdAddr, ChargeState, ReadOrigin, ZmkError};
+
+    const KEYBOARD: &str = "AA:BB:CC:DD:EE:01";
+
+    fn address() -> BdAddr {
+        KEYBOARD.parse().unwrap()
+    }
+
+    /// A runner with one hook and the device status of its config
+    fn setup() -> (HookRunner, Vec<DeviceStatus>) {
+        let config: Config = toml::from_str(&format!(
+            r#"
+            [[devices]]
+            name = "Corne"
+            address = "{KEYBOARD}"
+            thresholds = {{ critical = 10 }}
+
+            [[hooks]]
+            command = "true"
+            "#
+        ))
+        .unwrap();
+        let devices = config
+            .devices
+            .iter()
+            .map(|device| DeviceStatus::new(device.clone(), device.read_options(&config.general)))
+            .collect();
+        (HookRunner::new(&config), devices)
+    }
+
+    /// Set the Central level and return the event for it
+    fn level(devices: &mut [DeviceStatus], level: u8) -> MonitorEvent {
+        let battery = BatteryInfo {
+            name: "Central".to_string(),
+            level,
+            source: BatterySource::Central,
+            retries: 0,
+            origin: ReadOrigin::Device,
+            reconnected: false,
+            charging: ChargeState::Discharging,
+        };
+        devices[0].batteries = vec![battery.clone()];
+        MonitorEvent::LevelChanged {
+            device: 0,
+            battery,
+            previous: None,
+        }
+    }
+
+    /// `(event, tier)` of the occurrences of `event`
+    fn events(
+        runner: &mut HookRunner,
+        event: &MonitorEvent,
+        devices: &[DeviceStatus],
+    ) -> Vec<(HookEvent, Option<String>)> {
+        runner
+            .occurrences(event, devices)
+            .into_iter()
+            .map(|occurrence| (occurrence.event, occurrence.tier))
+            .collect()
+    }
+
+    #[test]
+    fn threshold_crossings_without_repeats() {
+        let (mut runner, mut devices) = setup();
+        let mut crossings = Vec::new();
+        for value in [50, 20, 19, 20, 18, 10, 9, 9, 24, 25, 30] {
+            let event = level(&mut devices, value);
+            for (event, tier) in events(&mut runner, &event, &devices) {
+                crossings.push((value, event, tier));
+            }
+        }
+        let tier = |name: &str| Some(name.to_string());
+        assert_eq!(
+            crossings,
+            [
+                (20, HookEvent::Threshold, tier("warning")),
+                (10, HookEvent::Threshold, tier("critical")),
+                (25, HookEvent::Recovered, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn charge_events() {
+        let (mut runner, devices) = setup();
+        let charge = |event| MonitorEvent::Charge {
+            device: 0,
+            battery: "Central".to_string(),
+            event,
+        };
+        let started = runner.occurrences(&charge(ChargeEvent::Started { level: 40 }), &devices);
+        let charged = runner.occurrences(&charge(ChargeEvent::Charged { level: 100 }), &devices);
+        let summary = |occurrences: Vec<Occurrence>| {
+            occurrences
+                .into_iter()
+                .map(|o| (o.event, o.battery, o.level))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            summary(started),
+            [(
+                HookEvent::ChargingStarted,
+                Some("Central".to_string()),
+                Some(40)
+            )]
+        );
+        assert_eq!(
+            summary(charged),
+            [(HookEvent::Charged, Some("Central".to_string()), Some(100))]
+        );
+    }
+
+    #[test]
+    fn connection_changes() {
+        let (mut runner, devices) = setup();
+        let device = |event| MonitorEvent::Device { device: 0, event };
+        let address = address();
+        for (event, expected) in [
+            (
+                DeviceEvent::Connected { address },
+                vec![HookEvent::Connected],
+            ),
+            (
+                DeviceEvent::Disconnected { address },
+                vec![HookEvent::Disconnected],
+            ),
+            (DeviceEvent::ServicesResolved { address }, vec![]),
+            (DeviceEvent::Removed { address }, vec![]),
+        ] {
+            let occurrences = runner.occurrences(&device(event), &devices);
+            let events: Vec<_> = occurrences.into_iter().map(|o| o.event).collect();
+            assert_eq!(events, expected);
+        }
+    }
+
+    #[test]
+    fn read_errors_after_a_good_read() {
+        let (mut runner, mut devices) = setup();
+        let mut refresh = |devices: &mut Vec<DeviceStatus>, error: Option<ZmkError>| {
+            devices[0].error = error;
+            events(&mut runner, &MonitorEvent::Refreshed, devices)
+        };
+        let asleep = || Some(ZmkError::Disconnected(address()));
+
+        // Asleep since before the start: nothing that stopped working
+        assert_eq!(refresh(&mut devices, asleep()), []);
+        assert_eq!(refresh(&mut devices, asleep()), []);
+        assert_eq!(refresh(&mut devices, None), []);
+        // Failing after a good read, once
+        assert_eq!(
+            refresh(&mut devices, asleep()),
+            [(HookEvent::ReadError, None)]
+        );
+        assert_eq!(refresh(&mut devices, asleep()), []);
+        assert_eq!(refresh(&mut devices, None), []);
+        assert_eq!(
+            refresh(&mut devices, asleep()),
+            [(HookEvent::ReadError, None)]
+        );
+    }
+
+    #[test]
+    fn environment_of_a_threshold_crossing() {
+        let (mut runner, mut devices) = setup();
+        let event = level(&mut devices, 9);
+        let occurrences = runner.occurrences(&event, &devices);
+        let env: BTreeMap<_, _> = environment(&occurrences[0], &devices[0])
+            .into_iter()
+            .collect();
+        assert_eq!(
+            env,
+            BTreeMap::from([
+                ("ZMK_ADDRESS", KEYBOARD.to_string()),
+                ("ZMK_BATTERY", "Central".to_string()),
+                ("ZMK_DEVICE", "Corne".to_string()),
+                ("ZMK_EVENT", "threshold".to_string()),
+                ("ZMK_LEVEL", "9".to_string()),
+                ("ZMK_TIER", "critical".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn environment_of_a_read_error() {
+        let (_, mut devices) = setup();
+        devices[0].address = None;
+        let occurrence = Occurrence {
+            error: Some("Device is asleep".to_string()),
+            ..Occurrence::new(HookEvent::ReadError, 0)
+        };
+        let env: BTreeMap<_, _> = environment(&occurrence, &devices[0]).into_iter().collect();
+        // Unknown details are left out rather than set empty
+        assert_eq!(
+            env,
+            BTreeMap::from([
+                ("ZMK_DEVICE", "Corne".to_string()),
+                ("ZMK_ERROR", "Device is asleep".to_string()),
+                ("ZMK_EVENT", "read-error".to_string()),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn commands_get_the_environment() {
+        let out = std::env::temp_dir().join(format!(
+            "zmk-battery-monitor-hook-{}.env",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&out);
+        let (mut runner, devices) = setup();
+        runner.hooks[0].command = format!(
+            "env | grep ^ZMK_ | sort > {}.tmp && mv {0}.tmp {0}",
+            out.display()
+        );
+        let event = MonitorEvent::Device {
+            device: 0,
+            event: DeviceEvent::Disconnected { address: address() },
+        };
+        runner.handle(&event, &Mutex::new(devices));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !out.exists() {
+            assert!(Instant::now() < deadline, "hook did not run");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            std::fs::read_to_string(&out).unwrap(),
+            format!("ZMK_ADDRESS={KEYBOARD}\nZMK_DEVICE=Corne\nZMK_EVENT=disconnected\n")
+        );
+        std::fs::remove_file(out).unwrap();
+    }
+}
@@ -1,216 +1,965 @@
-use anyhow::{Context, Result};
+use futures_util::stream::{self, BoxStream, StreamExt};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use tree::{Characteristic, Descriptor, Device, ManagedObjects};
 use zbus::{zvariant, Connection};
 
+pub mod address;
+pub mod backend;
 pub mod config;
+pub mod discovery;
+pub mod error;
+pub mod estimate;
+pub mod events;
+pub mod format;
+pub mod history;
+pub mod hooks;
+pub mod metrics;
+pub mod monitor;
+pub mod notifications;
+pub mod retry;
+pub mod tree;
+pub use address::{BdAddr, ParseBdAddrError};
+pub use backend::{BatteryBackend, FakeBackend};
 pub use config::Config;
+use config::DeviceConfig;
+pub use discovery::DiscoveryStream;
+pub use error::ZmkError;
+pub use estimate::{ChargeEvent, DischargeEstimate, LevelTrend};
+pub use events::{BackendState, BackendStateStream, DeviceEvent, DeviceEventStream};
+pub use history::{History, Sample};
+pub use hooks::{HookEvent, HookRunner};
+pub use monitor::{DeviceStatus, Monitor, MonitorEvent, RefreshHandle};
+pub use notifications::Notifier;
+pub use retry::RetryPolicy;
+pub use tree::ObjectTree;
+
+pub type Result<T, E = ZmkError> = std::result::Result<T, E>;
 
 pub const BATTERY_UUID: &str = "0000180f-0000-1000-8000-00805f9b34fb";
 pub const BATTERY_LEVEL_UUID: &str = "00002a19-0000-1000-8000-00805f9b34fb";
 pub const BATTERY_USER_DESC: &str = "00002901-0000-1000-8000-00805f9b34fb";
+pub const HID_UUID: &str = "00001812-0000-1000-8000-00805f9b34fb";
+pub const DEVICE_INFO_UUID: &str = "0000180a-0000-1000-8000-00805f9b34fb";
+pub const MANUFACTURER_NAME_UUID: &str = "00002a29-0000-1000-8000-00805f9b34fb";
+pub const MODEL_NUMBER_UUID: &str = "00002a24-0000-1000-8000-00805f9b34fb";
+pub const SERIAL_NUMBER_UUID: &str = "00002a25-0000-1000-8000-00805f9b34fb";
+pub const HARDWARE_REVISION_UUID: &str = "00002a27-0000-1000-8000-00805f9b34fb";
+pub const FIRMWARE_REVISION_UUID: &str = "00002a26-0000-1000-8000-00805f9b34fb";
 
 #[derive(Debug, Clone)]
 pub struct BatteryInfo {
     pub name: String,
     pub level: u8,
+    pub source: BatterySource,
+    /// Retries it took to read the level
+    pub retries: u32,
+    pub origin: ReadOrigin,
+    /// The device was disconnected and got connected for this read
+    pub reconnected: bool,
+    /// Inferred from the levels seen over time; unknown for a single read
+    pub charging: ChargeState,
 }
 
+/// Whether a battery is being charged, as told by its level trend
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChargeState {
+    #[default]
+    Unknown,
+    Discharging,
+    Charging,
+    /// Charged to 100% and not discharging yet
+    Full,
+}
+
+impl fmt::Display for ChargeState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Unknown => "unknown",
+            Self::Discharging => "discharging",
+            Self::Charging => "charging",
+            Self::Full => "fully charged",
+        })
+    }
+}
+
+/// Where a battery level came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadOrigin {
+    /// BlueZ's cached `Value` from an earlier read or notification
+    Cache,
+    /// A `ReadValue` call or notification from the keyboard
+    Device,
+}
+
+/// How battery levels are obtained
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReadStrategy {
+    /// Only use BlueZ's cached value; batteries without one are skipped.
+    /// Never wakes the keyboard radio.
+    Cached,
+    /// Always read from the keyboard
+    #[default]
+    Live,
+    /// Use the cached value if there is one, otherwise read from the keyboard
+    CachedThenLive,
+}
+
+/// Which part of a split keyboard a battery belongs to.
+///
+/// Orders Central first, then peripherals by index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum BatterySource {
+    Central,
+    Peripheral(u8),
+    Unknown,
+}
+
+impl BatterySource {
+    /// Classify a battery by its user description as set by ZMK: the central
+    /// reports none or "Battery", peripherals report "Peripheral <n>"
+    pub fn from_description(description: Option<&str>) -> Self {
+        match description.map(str::trim) {
+            None | Some("Battery") | Some("Central") => Self::Central,
+            Some(desc) => match desc.strip_prefix("Peripheral").map(str::trim) {
+                Some("") => Self::Peripheral(0),
+                Some(index) => index.parse().map_or(Self::Unknown, Self::Peripheral),
+                None => Self::Unknown,
+            },
+        }
+    }
+
+    /// Key used in the `battery_names` config map, e.g. `peripheral1`
+    pub fn key(&self) -> Option<String> {
+        match self {
+            Self::Central => Some("central".to_string()),
+            Self::Peripheral(index) => Some(format!("peripheral{index}")),
+            Self::Unknown => None,
+        }
+    }
+}
+
+/// Sort batteries Central first, then by peripheral index
+pub fn sort_batteries(batteries: &mut [BatteryInfo]) {
+    batteries.sort_by(|a, b| a.source.cmp(&b.source).then_with(|| a.name.cmp(&b.name)));
+}
+
+/// A device known to BlueZ, from `org.bluez.Device1`
+#[derive(Debug, Clone, Default)]
+pub struct DeviceInfo {
+    pub name: Option<String>,
+    pub alias: Option<String>,
+    pub address: BdAddr,
+    /// Adapter the device belongs to, e.g. `hci0`
+    pub adapter: String,
+    pub paired: bool,
+    pub bonded: bool,
+    pub connected: bool,
+    pub trusted: bool,
+    pub services_resolved: bool,
+    pub icon: Option<String>,
+    pub appearance: Option<u16>,
+    pub rssi: Option<i16>,
+    /// Advertised service UUIDs
+    pub uuids: Vec<String>,
+    /// Whether a Battery Service is present in the device's GATT tree
+    pub has_battery_service: bool,
+}
+
+impl DeviceInfo {
+    fn from_device(device: &Device) -> Self {
+        Self {
+            name: device.name.clone(),
+            alias: device.alias.clone(),
+            address: device.address,
+            adapter: device.adapter.clone(),
+            paired: device.paired,
+            bonded: device.bonded,
+            connected: device.connected,
+            trusted: device.trusted,
+            services_resolved: device.services_resolved,
+            icon: device.icon.clone(),
+            appearance: device.appearance,
+            rssi: device.rssi,
+            uuids: device.uuids.clone(),
+            has_battery_service: device.has_battery_service(),
+        }
+    }
+
+    /// Name to show to the user: alias, then name, then address
+    pub fn display_name(&self) -> String {
+        self.alias
+            .clone()
+            .or_else(|| self.name.clone())
+            .unwrap_or_else(|| self.address.to_string())
+    }
+
+    /// Whether this is an HID device (keyboard, mouse, ...)
+    pub fn is_hid(&self) -> bool {
+        // Appearance category 0x0F is "Human Interface Device"
+        let hid_appearance = self.appearance.is_some_and(|a| a >> 6 == 0x0F);
+        let hid_icon = self
+            .icon
+            .as_deref()
+            .is_some_and(|icon| icon.starts_with("input-"));
+        hid_appearance || hid_icon || self.uuids.iter().any(|uuid| uuid == HID_UUID)
+    }
+
+    /// Whether the device reports battery levels, either through a resolved
+    /// Battery Service or by advertising one
+    pub fn reports_battery(&self) -> bool {
+        self.has_battery_service || self.uuids.iter().any(|uuid| uuid == BATTERY_UUID)
+    }
+
+    /// Whether this looks like a ZMK keyboard: an HID device with battery
+    /// reporting
+    pub fn is_zmk_candidate(&self) -> bool {
+        self.is_hid() && self.reports_battery()
+    }
+}
+
+/// Contents of the Device Information Service; fields the device does not
+/// expose are `None`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceInformation {
+    pub manufacturer: Option<String>,
+    pub model_number: Option<String>,
+    pub serial_number: Option<String>,
+    pub hardware_revision: Option<String>,
+    pub firmware_revision: Option<String>,
+}
+
+impl DeviceInformation {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl fmt::Display for DeviceInformation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fields = [
+            ("Manufacturer", &self.manufacturer),
+            ("Model", &self.model_number),
+            ("Hardware", &self.hardware_revision),
+            ("Firmware", &self.firmware_revision),
+        ];
+        let parts: Vec<_> = fields
+            .iter()
+            .filter_map(|(label, value)| value.as_ref().map(|v| format!("{label}: {v}")))
+            .collect();
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+/// Per-device read settings; unset fields fall back to the reader's defaults
+#[derive(Debug, Clone, Default)]
+pub struct ReadOptions {
+    /// Only look for the device on this adapter, e.g. `hci1`
+    pub adapter: Option<String>,
+    pub retry: Option<RetryPolicy>,
+    pub strategy: Option<ReadStrategy>,
+    /// Connect the device if it is disconnected, allowing this long for the
+    /// connection and service discovery; disconnected devices fail if unset
+    pub connect_timeout: Option<Duration>,
+}
+
+/// Result of reading one configured device
+#[derive(Debug, Clone)]
+pub struct DeviceReading {
+    pub device: DeviceConfig,
+    /// Address the device config resolved to, if any device matched
+    pub address: Option<BdAddr>,
+    pub batteries: Result<Vec<BatteryInfo>>,
+}
+
+/// Run `read` for every enabled device of `config` with the concurrency
+/// limit and per-device timeout from `[general]`, then apply battery names.
+///
+/// Devices configured without an address are looked up in `known`.
+pub(crate) async fn read_concurrently<F, Fut>(
+    config: &Config,
+    known: &Result<Vec<DeviceInfo>>,
+    read: F,
+) -> Vec<DeviceReading>
+where
+    F: Fn(BdAddr, ReadOptions) -> Fut,
+    Fut: Future<Output = Result<Vec<BatteryInfo>>>,
+{
+    let limit = config.general.max_concurrent_reads.max(1);
+    let timeout = Duration::from_millis(config.general.device_timeout_ms);
+
+    let mut reads = Vec::new();
+    for device in config.get_enabled_devices() {
+        let address = match known {
+            Ok(known) => device.resolve_address(known),
+            Err(e) => device.address.ok_or_else(|| e.clone()),
+        };
+        let read = address
+            .clone()
+            .map(|address| read(address, device.read_options(&config.general)));
+        reads.push(async move {
+            let read = match read {
+                Ok(read) => tokio::time::timeout(timeout, read).await,
+                Err(e) => Ok(Err(e)),
+            };
+            let batteries = match read {
+                Ok(Ok(mut batteries)) => {
+                    device.apply_battery_names(&mut batteries);
+                    Ok(batteries)
+                }
+                Ok(Err(e)) => Err(e),
+                Err(_) => Err(ZmkError::Timeout(timeout)),
+            };
+            DeviceReading {
+                device: device.clone(),
+                address: address.ok(),
+                batteries,
+            }
+        });
+    }
+
+    stream::iter(reads).buffered(limit).collect().await
+}
+
+/// Stream of battery level updates pushed by the device
+pub type BatteryStream = BoxStream<'static, BatteryInfo>;
+
 pub struct ZmkBatteryReader {
     conn: Connection,
+    retry: RetryPolicy,
+    strategy: ReadStrategy,
 }
 
 impl ZmkBatteryReader {
     pub async fn new() -> Result<Self> {
         let conn = Connection::system().await?;
-        Ok(Self { conn })
+        Ok(Self {
+            conn,
+            retry: RetryPolicy::default(),
+            strategy: ReadStrategy::default(),
+        })
     }
 
-    pub async fn read_battery_levels(&self, device_address: &str) -> Result<Vec<BatteryInfo>> {
-        let device_path = format!(
-            "/org/bluez/hci0/dev_{}",
-            device_address.replace([':', '-'], "_")
-        );
+    /// Set the read strategy used when `ReadOptions` does not specify one
+    pub fn with_read_strategy(mut self, strategy: ReadStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
 
-        // Get all managed objects
-        let proxy = zbus::Proxy::new(
-            &self.conn,
-            "org.bluez",
-            "/",
-            "org.freedesktop.DBus.ObjectManager",
-        )
-        .await?;
+    /// Set the retry policy used when `ReadOptions` does not specify one
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
 
-        let reply = proxy.call_method("GetManagedObjects", &()).await?;
-        let managed_objects: HashMap<
-            zvariant::OwnedObjectPath,
-            HashMap<String, HashMap<String, zvariant::OwnedValue>>,
-        > = reply.body().deserialize()?;
+    /// Fetch and parse the current BlueZ object tree
+    pub async fn object_tree(&self) -> Result<ObjectTree> {
+        let (managed_objects, _) = self
+            .retry
+            .run(|| async {
+                let proxy = zbus::Proxy::new(
+                    &self.conn,
+                    "org.bluez",
+                    "/",
+                    "org.freedesktop.DBus.ObjectManager",
+                )
+                .await?;
+
+                let reply = proxy.call_method("GetManagedObjects", &()).await?;
+                let managed_objects: ManagedObjects = reply.body().deserialize()?;
+                Ok(managed_objects)
+            })
+            .await?;
+        ObjectTree::parse(&managed_objects)
+    }
 
-        let mut batteries = Vec::new();
+    /// Read all battery levels of a device.
+    ///
+    /// The device is looked up on `options.adapter` if given, otherwise on
+    /// any adapter. Levels are taken from BlueZ's cache or the keyboard
+    /// according to the read strategy; live reads are retried according to
+    /// the retry policy.
+    pub async fn read_battery_levels(
+        &self,
+        device_address: BdAddr,
+        options: &ReadOptions,
+    ) -> Result<Vec<BatteryInfo>> {
+        let tree = self.object_tree().await?;
+        self.read_battery_levels_in(&tree, device_address, options)
+            .await
+    }
 
-        // Find battery services
-        for (path, interfaces) in managed_objects.iter() {
-            let path_str = path.as_str();
-            if !path_str.starts_with(&device_path) {
-                continue;
+    /// Read every enabled device of `config` concurrently from one snapshot
+    /// of the object tree.
+    ///
+    /// At most `general.max_concurrent_reads` devices are read at a time and
+    /// each device gets `general.device_timeout_ms` in total. Results are in
+    /// config order with the configured battery names applied.
+    pub async fn read_all(&self, config: &Config) -> Vec<DeviceReading> {
+        let tree = match self.object_tree().await {
+            Ok(tree) => tree,
+            Err(e) => {
+                return config
+                    .get_enabled_devices()
+                    .into_iter()
+                    .map(|device| DeviceReading {
+                        device: device.clone(),
+                        address: device.address,
+                        batteries: Err(e.clone()),
+                    })
+                    .collect();
             }
+        };
+
+        let known = Ok(tree.devices().map(DeviceInfo::from_device).collect());
+        read_concurrently(config, &known, |address, options| {
+            let tree = &tree;
+            async move { self.read_battery_levels_in(tree, address, &options).await }
+        })
+        .await
+    }
 
-            if let Some(service_props) = interfaces.get("org.bluez.GattService1") {
-                if let Some(uuid_value) = service_props.get("UUID") {
-                    let service_uuid: String = uuid_value.try_to_owned()?.try_into()?;
-
-                    if service_uuid == BATTERY_UUID {
-                        // Find battery characteristics
-                        if let Some(battery_info) = self
-                            .read_battery_from_service(path_str, &managed_objects)
-                            .await?
-                        {
-                            batteries.push(battery_info);
-                        }
-                    }
-                }
+    async fn read_battery_levels_in(
+        &self,
+        tree: &ObjectTree,
+        device_address: BdAddr,
+        options: &ReadOptions,
+    ) -> Result<Vec<BatteryInfo>> {
+        let adapter = options.adapter.as_deref();
+        let device = match (
+            resolve_device(tree, device_address, adapter),
+            options.connect_timeout,
+        ) {
+            (Err(ZmkError::Disconnected(_) | ZmkError::ServicesNotResolved(_)), Some(timeout)) => {
+                let device = tree
+                    .find_device(device_address, adapter)
+                    .ok_or_else(|| ZmkError::NotPaired(device_address))?;
+                let reconnected = !device.connected;
+                self.connect(device, timeout).await?;
+
+                // The snapshot predates the connection and lacks the services
+                let tree = self.object_tree().await?;
+                let device = resolve_device(&tree, device_address, adapter)?;
+                return self
+                    .read_device_batteries(device, options, reconnected)
+                    .await;
             }
+            (device, _) => device?,
+        };
+        self.read_device_batteries(device, options, false).await
+    }
+
+    async fn read_device_batteries(
+        &self,
+        device: &Device,
+        options: &ReadOptions,
+        reconnected: bool,
+    ) -> Result<Vec<BatteryInfo>> {
+        let retry = options.retry.unwrap_or(self.retry);
+        let strategy = options.strategy.unwrap_or(self.strategy);
+        let characteristics = battery_level_characteristics(device)?;
+        let labels = self
+            .battery_labels(&characteristics, &retry, strategy)
+            .await;
+
+        let mut batteries = Vec::new();
+        for (characteristic, (source, name)) in characteristics.into_iter().zip(labels) {
+            let cached = characteristic.value.as_ref().filter(|v| !v.is_empty());
+            let (battery_data, retries, origin) = match (strategy, cached) {
+                (ReadStrategy::Cached | ReadStrategy::CachedThenLive, Some(value)) => {
+                    (value.clone(), 0, ReadOrigin::Cache)
+                }
+                (ReadStrategy::Cached, None) => continue,
+                (ReadStrategy::Live | ReadStrategy::CachedThenLive, _) => {
+                    let (value, retries) = self.read_value(characteristic, &retry).await?;
+                    (value, retries, ReadOrigin::Device)
+                }
+            };
+            let level = battery_data.first().copied().unwrap_or(0);
+
+            batteries.push(BatteryInfo {
+                name,
+                level,
+                source,
+                retries,
+                origin,
+                reconnected,
+                charging: ChargeState::Unknown,
+            });
         }
 
+        sort_batteries(&mut batteries);
         Ok(batteries)
     }
 
-    async fn read_battery_from_service(
+    /// Read the Device Information Service of a device.
+    ///
+    /// These values do not change at runtime, so BlueZ's cached values are
+    /// used where available; others are read from the keyboard unless the
+    /// strategy is `Cached`. Every field is best-effort: one that cannot be
+    /// read is left `None`.
+    pub async fn read_device_info(
         &self,
-        service_path: &str,
-        managed_objects: &HashMap<
-            zvariant::OwnedObjectPath,
-            HashMap<String, HashMap<String, zvariant::OwnedValue>>,
-        >,
-    ) -> Result<Option<BatteryInfo>> {
-        for (char_path, char_interfaces) in managed_objects.iter() {
-            let char_path_str = char_path.as_str();
-            if !char_path_str.starts_with(service_path) || char_path_str == service_path {
+        device_address: BdAddr,
+        options: &ReadOptions,
+    ) -> Result<DeviceInformation> {
+        let tree = self.object_tree().await?;
+        let device = resolve_device(&tree, device_address, options.adapter.as_deref())?;
+
+        let retry = options.retry.unwrap_or(self.retry);
+        let strategy = options.strategy.unwrap_or(self.strategy);
+        let mut info = DeviceInformation::default();
+        let Some(service) = device.services_with_uuid(DEVICE_INFO_UUID).next() else {
+            return Ok(info);
+        };
+
+        for (uuid, field) in [
+            (MANUFACTURER_NAME_UUID, &mut info.manufacturer),
+            (MODEL_NUMBER_UUID, &mut info.model_number),
+            (SERIAL_NUMBER_UUID, &mut info.serial_number),
+            (HARDWARE_REVISION_UUID, &mut info.hardware_revision),
+            (FIRMWARE_REVISION_UUID, &mut info.firmware_revision),
+        ] {
+            let Some(characteristic) = service.characteristic(uuid) else {
                 continue;
+            };
+            let cached = characteristic.value.as_ref().filter(|v| !v.is_empty());
+            let data = match (strategy, cached) {
+                (_, Some(value)) => value.clone(),
+                (ReadStrategy::Cached, None) => continue,
+                (ReadStrategy::Live | ReadStrategy::CachedThenLive, None) => {
+                    match self.read_value(characteristic, &retry).await {
+                        Ok((value, _)) => value,
+                        Err(_) => continue,
+                    }
+                }
+            };
+            *field = String::from_utf8(data)
+                .ok()
+                .map(|s| s.trim_end_matches('\0').trim().to_string())
+                .filter(|s| !s.is_empty());
+        }
+
+        Ok(info)
+    }
+
+    /// Connect a device if needed and wait until its services are resolved
+    async fn connect(&self, device: &Device, timeout: Duration) -> Result<()> {
+        let props_proxy = zbus::Proxy::new(
+            &self.conn,
+            "org.bluez",
+            device.path.as_str(),
+            "org.freedesktop.DBus.Properties",
+        )
+        .await?;
+        let mut changes = props_proxy.receive_signal("PropertiesChanged").await?;
+
+        let device_proxy = zbus::Proxy::new(
+            &self.conn,
+            "org.bluez",
+            device.path.as_str(),
+            "org.bluez.Device1",
+        )
+        .await?;
+
+        let connect = async {
+            if !device.connected {
+                device_proxy.call_method("Connect", &()).await?;
             }
 
-            if let Some(char_props) = char_interfaces.get("org.bluez.GattCharacteristic1") {
-                if let Some(char_uuid_value) = char_props.get("UUID") {
-                    let char_uuid: String = char_uuid_value.try_to_owned()?.try_into()?;
-
-                    if char_uuid == BATTERY_LEVEL_UUID {
-                        // Read battery level
-                        let char_proxy = zbus::Proxy::new(
-                            &self.conn,
-                            "org.bluez",
-                            char_path_str,
-                            "org.bluez.GattCharacteristic1",
-                        )
-                        .await?;
-
-                        let options: HashMap<String, zvariant::Value> = HashMap::new();
-                        let reply = char_proxy
-                            .call_method("ReadValue", &(options,))
-                            .await
-                            .context("Failed to read battery value")?;
-
-                        let battery_data: Vec<u8> = reply.body().deserialize()?;
-                        let level = battery_data.first().copied().unwrap_or(0);
-
-                        // Get battery name from descriptor
-                        let mut name = self
-                            .read_battery_name(char_path_str, managed_objects)
-                            .await?
-                            .unwrap_or_else(|| "Battery".to_string());
-
-                        // Map ZMK names to user-friendly names
-                        name = match name.as_str() {
-                            "Battery" => "Central".to_string(),
-                            "Peripheral 0" => "Peripheral".to_string(),
-                            _ => name,
-                        };
-
-                        return Ok(Some(BatteryInfo { name, level }));
-                    }
+            // Connect can return before service discovery has finished
+            let resolved: bool = device_proxy.get_property("ServicesResolved").await?;
+            if resolved {
+                return Ok(());
+            }
+            while let Some(message) = changes.next().await {
+                let Ok((interface, changed, _invalidated)) = message.body().deserialize::<(
+                    String,
+                    HashMap<String, zvariant::OwnedValue>,
+                    Vec<String>,
+                )>() else {
+                    continue;
+                };
+                let resolved = changed
+                    .get("ServicesResolved")
+                    .and_then(|v| bool::try_from(v).ok());
+                if interface == "org.bluez.Device1" && resolved == Some(true) {
+                    return Ok(());
                 }
             }
-        }
+            Err(ZmkError::Disconnected(device.address))
+        };
 
-        Ok(None)
+        tokio::time::timeout(timeout, connect)
+            .await
+            .map_err(|_| ZmkError::Timeout(timeout))?
     }
 
+    /// Read a characteristic, returning the value and the retries it took
+    async fn read_value(
+        &self,
+        characteristic: &Characteristic,
+        retry: &RetryPolicy,
+    ) -> Result<(Vec<u8>, u32)> {
+        retry
+            .run(|| async {
+                let char_proxy = zbus::Proxy::new(
+                    &self.conn,
+                    "org.bluez",
+                    characteristic.path.as_str(),
+                    "org.bluez.GattCharacteristic1",
+                )
+                .await?;
+
+                let options: HashMap<String, zvariant::Value> = HashMap::new();
+                let reply = char_proxy.call_method("ReadValue", &(options,)).await?;
+                Ok(reply.body().deserialize()?)
+            })
+            .await
+    }
+
+    /// Read the User Description of a battery characteristic, from BlueZ's
+    /// cache where the read strategy allows it
     async fn read_battery_name(
         &self,
-        char_path: &str,
-        managed_objects: &HashMap<
-            zvariant::OwnedObjectPath,
-            HashMap<String, HashMap<String, zvariant::OwnedValue>>,
-        >,
-    ) -> Result<Option<String>> {
-        for (desc_path, desc_interfaces) in managed_objects.iter() {
-            let desc_path_str = desc_path.as_str();
-            if !desc_path_str.starts_with(char_path) || desc_path_str == char_path {
-                continue;
+        characteristic: &Characteristic,
+        retry: &RetryPolicy,
+        strategy: ReadStrategy,
+    ) -> Option<String> {
+        let descriptor = characteristic.descriptor(BATTERY_USER_DESC)?;
+        let cached = descriptor.value.as_ref().filter(|v| !v.is_empty());
+
+        let desc_data = match (strategy, cached) {
+            (ReadStrategy::Cached | ReadStrategy::CachedThenLive, Some(value)) => value.clone(),
+            (ReadStrategy::Cached, None) => return None,
+            (ReadStrategy::Live | ReadStrategy::CachedThenLive, _) => {
+                self.read_descriptor(descriptor, retry).await?
             }
+        };
+
+        let desc_str = String::from_utf8(desc_data).ok()?;
+        Some(desc_str.trim_end_matches('\0').to_string())
+    }
 
-            if let Some(desc_props) = desc_interfaces.get("org.bluez.GattDescriptor1") {
-                if let Some(desc_uuid_value) = desc_props.get("UUID") {
-                    let desc_uuid: String = desc_uuid_value.try_to_owned()?.try_into()?;
-
-                    if desc_uuid == BATTERY_USER_DESC {
-                        let desc_proxy = zbus::Proxy::new(
-                            &self.conn,
-                            "org.bluez",
-                            desc_path_str,
-                            "org.bluez.GattDescriptor1",
-                        )
-                        .await?;
-
-                        let desc_options: HashMap<String, zvariant::Value> = HashMap::new();
-                        if let Ok(reply) =
-                            desc_proxy.call_method("ReadValue", &(desc_options,)).await
-                        {
-                            if let Ok(desc_data) = reply.body().deserialize::<Vec<u8>>() {
-                                if let Ok(desc_str) = String::from_utf8(desc_data) {
-                                    return Ok(Some(desc_str.trim_end_matches('\0').to_string()));
-                                }
-                            }
-                        }
+    /// Read a descriptor from the keyboard
+    async fn read_descriptor(
+        &self,
+        descriptor: &Descriptor,
+        retry: &RetryPolicy,
+    ) -> Option<Vec<u8>> {
+        let (desc_data, _) = retry
+            .run(|| async {
+                let desc_proxy = zbus::Proxy::new(
+                    &self.conn,
+                    "org.bluez",
+                    descriptor.path.as_str(),
+                    "org.bluez.GattDescriptor1",
+                )
+                .await?;
+
+                let desc_options: HashMap<String, zvariant::Value> = HashMap::new();
+                let reply = desc_proxy
+                    .call_method("ReadValue", &(desc_options,))
+                    .await?;
+                let desc_data: Vec<u8> = reply.body().deserialize()?;
+                Ok(desc_data)
+            })
+            .await
+            .ok()?;
+        Some(desc_data)
+    }
+
+    /// Subscribe to battery level notifications from a device.
+    ///
+    /// Calls `StartNotify` on every Battery Level characteristic and yields a
+    /// `BatteryInfo` whenever BlueZ reports a new `Value`. BlueZ stops the
+    /// notifications once this connection goes away. The stream ends when
+    /// BlueZ itself goes away, as the notifications are lost with it.
+    pub async fn subscribe_battery_levels(
+        &self,
+        device_address: BdAddr,
+        options: &ReadOptions,
+    ) -> Result<BatteryStream> {
+        let tree = self.object_tree().await?;
+        let device = resolve_device(&tree, device_address, options.adapter.as_deref())?;
+
+        let retry = options.retry.unwrap_or(self.retry);
+        let strategy = options.strategy.unwrap_or(self.strategy);
+        let characteristics = battery_level_characteristics(device)?;
+        let labels = self
+            .battery_labels(&characteristics, &retry, strategy)
+            .await;
+
+        let lost = self.backend_lost().await?;
+        let mut streams = Vec::new();
+
+        for (characteristic, (source, name)) in characteristics.into_iter().zip(labels) {
+            let props_proxy = zbus::Proxy::new(
+                &self.conn,
+                "org.bluez",
+                characteristic.path.clone(),
+                "org.freedesktop.DBus.Properties",
+            )
+            .await?;
+            let changes = props_proxy.receive_signal("PropertiesChanged").await?;
+
+            let char_proxy = zbus::Proxy::new(
+                &self.conn,
+                "org.bluez",
+                characteristic.path.as_str(),
+                "org.bluez.GattCharacteristic1",
+            )
+            .await?;
+            retry
+                .run(|| async { Ok(char_proxy.call_method("StartNotify", &()).await?) })
+                .await?;
+
+            let updates = changes.filter_map(move |message| {
+                let name = name.clone();
+                async move {
+                    let (interface, changed, _invalidated): (
+                        String,
+                        HashMap<String, zvariant::OwnedValue>,
+                        Vec<String>,
+                    ) = message.body().deserialize().ok()?;
+                    if interface != "org.bluez.GattCharacteristic1" {
+                        return None;
                     }
+                    let value: Vec<u8> =
+                        changed.get("Value")?.try_to_owned().ok()?.try_into().ok()?;
+                    let level = value.first().copied()?;
+                    Some(BatteryInfo {
+                        name,
+                        level,
+                        source,
+                        retries: 0,
+                        origin: ReadOrigin::Device,
+                        reconnected: false,
+                        charging: ChargeState::Unknown,
+                    })
                 }
-            }
+            });
+            streams.push(updates.boxed());
         }
 
-        Ok(None)
+        Ok(stream::select_all(streams).take_until(lost).boxed())
     }
 
-    pub async fn list_devices(&self) -> Result<Vec<(String, String)>> {
-        let proxy = zbus::Proxy::new(
-            &self.conn,
-            "org.bluez",
-            "/",
-            "org.freedesktop.DBus.ObjectManager",
-        )
-        .await?;
+    /// Classify battery characteristics and give them default names
+    async fn battery_labels(
+        &self,
+        characteristics: &[&Characteristic],
+        retry: &RetryPolicy,
+        strategy: ReadStrategy,
+    ) -> Vec<(BatterySource, String)> {
+        let mut descriptions = Vec::new();
+        for characteristic in characteristics {
+            descriptions.push(
+                self.read_battery_name(characteristic, retry, strategy)
+                    .await,
+            );
+        }
+        label_batteries(descriptions)
+    }
 
-        let reply = proxy.call_method("GetManagedObjects", &()).await?;
-        let managed_objects: HashMap<
-            zvariant::OwnedObjectPath,
-            HashMap<String, HashMap<String, zvariant::OwnedValue>>,
-        > = reply.body().deserialize()?;
-
-        let mut devices = Vec::new();
-
-        for (_path, interfaces) in managed_objects.iter() {
-            if let Some(device_props) = interfaces.get("org.bluez.Device1") {
-                if let (Some(name_value), Some(address_value)) =
-                    (device_props.get("Name"), device_props.get("Address"))
-                {
-                    if let (Ok(name), Ok(address)) = (
-                        name_value.try_to_owned()?.try_into(),
-                        address_value.try_to_owned()?.try_into(),
-                    ) {
-                        devices.push((name, address));
-                    }
-                }
-            }
+    /// List all devices known to BlueZ
+    pub async fn list_devices(&self) -> Result<Vec<DeviceInfo>> {
+        let tree = self.object_tree().await?;
+        Ok(tree.devices().map(DeviceInfo::from_device).collect())
+    }
+}
+
+/// Sources and default names of batteries with the given user descriptions.
+///
+/// A lone peripheral is called "Peripheral"; with several, each keeps its
+/// index so labels stay consistent.
+fn label_batteries(descriptions: Vec<Option<String>>) -> Vec<(BatterySource, String)> {
+    let sources: Vec<_> = descriptions
+        .iter()
+        .map(|desc| BatterySource::from_description(desc.as_deref()))
+        .collect();
+    let peripherals = sources
+        .iter()
+        .filter(|source| matches!(source, BatterySource::Peripheral(_)))
+        .count();
+
+    sources
+        .into_iter()
+        .zip(descriptions)
+        .map(|(source, description)| {
+            let name = match source {
+                BatterySource::Central => "Central".to_string(),
+                BatterySource::Peripheral(_) if peripherals == 1 => "Peripheral".to_string(),
+                BatterySource::Peripheral(index) => format!("Peripheral {index}"),
+                BatterySource::Unknown => description.unwrap_or_default(),
+            };
+            (source, name)
+        })
+        .collect()
+}
+
+/// Find a device and check that its GATT services can be used
+fn resolve_device<'a>(
+    tree: &'a ObjectTree,
+    device_address: BdAddr,
+    adapter: Option<&str>,
+) -> Result<&'a Device> {
+    let device = tree
+        .find_device(device_address, adapter)
+        .ok_or_else(|| ZmkError::NotPaired(device_address))?;
+
+    if !device.connected {
+        if !device.paired && !device.bonded {
+            return Err(ZmkError::NotPaired(device_address));
+        }
+        return Err(ZmkError::Disconnected(device_address));
+    }
+
+    if !device.services_resolved {
+        return Err(ZmkError::ServicesNotResolved(device_address));
+    }
+
+    Ok(device)
+}
+
+/// Battery Level characteristics of every Battery Service of a device
+fn battery_level_characteristics(device: &Device) -> Result<Vec<&Characteristic>> {
+    if !device.has_battery_service() {
+        return Err(ZmkError::NoBatteryService(device.address));
+    }
+
+    Ok(device
+        .services_with_uuid(BATTERY_UUID)
+        .filter_map(|service| service.characteristic(BATTERY_LEVEL_UUID))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn battery_sources_from_descriptions() {
+        for (description, expected) in [
+            (None, BatterySource::Central),
+            (Some("Battery"), BatterySource::Central),
+            (Some("Central"), BatterySource::Central),
+            (Some(" Central "), BatterySource::Central),
+            (Some("Peripheral 1"), BatterySource::Peripheral(1)),
+            (Some("Peripheral 12"), BatterySource::Peripheral(12)),
+            (Some("Peripheral1"), BatterySource::Peripheral(1)),
+            (Some("Peripheral"), BatterySource::Peripheral(0)),
+            (Some("PeripheralX"), BatterySource::Unknown),
+            (Some("Peripheral -1"), BatterySource::Unknown),
+            (Some("Peripheral 256"), BatterySource::Unknown),
+            (Some("peripheral 1"), BatterySource::Unknown),
+            (Some("Left half"), BatterySource::Unknown),
+            (Some(""), BatterySource::Unknown),
+        ] {
+            assert_eq!(
+                BatterySource::from_description(description),
+                expected,
+                "{description:?}"
+            );
+        }
+    }
+
+    fn device(appearance: Option<u16>, icon: Option<&str>, uuids: &[&str]) -> DeviceInfo {
+        DeviceInfo {
+            appearance,
+            icon: icon.map(str::to_string),
+            uuids: uuids.iter().map(|uuid| uuid.to_string()).collect(),
+            ..Default::default()
         }
+    }
+
+    #[test]
+    fn hid_classification() {
+        const KEYBOARD: u16 = 0x03C1;
+        const MOUSE: u16 = 0x03C2;
+        const GENERIC_HID: u16 = 0x03C0;
+        const PHONE: u16 = 0x0040;
+        const HEADSET: u16 = 0x0941;
+        for (appearance, icon, uuids, hid) in [
+            (Some(KEYBOARD), None, &[][..], true),
+            (Some(MOUSE), None, &[], true),
+            (Some(GENERIC_HID), None, &[], true),
+            (Some(PHONE), None, &[], false),
+            (Some(HEADSET), Some("audio-headset"), &[], false),
+            (None, Some("input-keyboard"), &[], true),
+            (None, Some("phone"), &[], false),
+            (None, None, &[HID_UUID], true),
+            (None, None, &[BATTERY_UUID], false),
+            // Any one of them is enough
+            (Some(PHONE), None, &[HID_UUID], true),
+            (None, None, &[], false),
+        ] {
+            assert_eq!(
+                device(appearance, icon, uuids).is_hid(),
+                hid,
+                "{appearance:?} {icon:?} {uuids:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn zmk_candidates() {
+        let keyboard = Some(0x03C1);
+        // HID with a battery, advertised or resolved
+        assert!(device(keyboard, None, &[BATTERY_UUID]).is_zmk_candidate());
+        assert!(device(None, None, &[HID_UUID, BATTERY_UUID]).is_zmk_candidate());
+        let mut resolved = device(keyboard, None, &[]);
+        assert!(!resolved.is_zmk_candidate());
+        resolved.has_battery_service = true;
+        assert!(resolved.is_zmk_candidate());
+
+        // A battery alone is not a keyboard
+        assert!(!device(Some(0x0941), Some("audio-headset"), &[BATTERY_UUID]).is_zmk_candidate());
+        assert!(!device(None, Some("input-keyboard"), &[HID_UUID]).is_zmk_candidate());
+    }
+
+    #[test]
+    fn battery_source_keys() {
+        assert_eq!(BatterySource::Central.key().as_deref(), Some("central"));
+        assert_eq!(
+            BatterySource::Peripheral(2).key().as_deref(),
+            Some("peripheral2")
+        );
+        assert_eq!(BatterySource::Unknown.key(), None);
+    }
 
-        Ok(devices)
+    fn labels(descriptions: &[Option<&str>]) -> Vec<String> {
+        label_batteries(descriptions.iter().map(|d| d.map(str::to_string)).collect())
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect()
+    }
+
+    #[test]
+    fn lone_peripheral_drops_its_index() {
+        assert_eq!(
+            labels(&[None, Some("Peripheral 0")]),
+            ["Central", "Peripheral"]
+        );
+    }
+
+    #[test]
+    fn several_peripherals_keep_their_index() {
+        assert_eq!(
+            labels(&[Some("Peripheral 1"), Some("Battery"), Some("Peripheral 0")]),
+            ["Peripheral 1", "Central", "Peripheral 0"]
+        );
+    }
+
+    #[test]
+    fn unknown_batteries_keep_their_description() {
+        assert_eq!(
+            labels(&[Some("Left half"), Some("Peripheral 1"), Some("PeripheralX")]),
+            ["Left half", "Peripheral", "PeripheralX"]
+        );
     }
 }
@@ -1,77 +1,229 @@
 use anyhow::Result;
-use zmk_battery_monitor::{Config, ZmkBatteryReader};
+use std::time::SystemTime;
+use zmk_battery_monitor::config::Tier;
+use zmk_battery_monitor::estimate::{format_remaining, ESTIMATE_WINDOW};
+use zmk_battery_monitor::{
+    BatteryBackend, BatteryInfo, ChargeState, Config, DischargeEstimate, History, LevelTrend,
+    ReadOrigin, ZmkBatteryReader, ZmkError,
+};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Load configuration
     let config = Config::load()?;
 
-    // Get the primary enabled device
-    let device = match config.get_primary_device() {
-        Some(d) => d,
-        None => {
-            eprintln!("No enabled devices found in config!");
-            eprintln!(
-                "Please edit the config file at: {}",
-                Config::config_path()?.display()
-            );
-            eprintln!("\nAvailable devices from bluetoothctl:");
-
-            // List available devices to help user
-            let reader = ZmkBatteryReader::new().await?;
-            if let Ok(devices) = reader.list_devices().await {
-                for (name, address) in devices {
-                    println!("  {name} - {address}");
-                }
-            }
-            return Ok(());
+    if config.get_enabled_devices().is_empty() {
+        eprintln!("No enabled devices found in config!");
+        eprintln!(
+            "Please edit the config file at: {}",
+            Config::config_path()?.display()
+        );
+        eprintln!("\nAvailable keyboards:");
+
+        // List available devices to help user
+        let reader = ZmkBatteryReader::new().await?;
+        print_available_devices(&reader).await;
+        return Ok(());
+    }
+
+    let reader = ZmkBatteryReader::new()
+        .await?
+        .with_retry_policy(config.general.retry_policy());
+
+    let mut history = History::open_enabled(&config.history).unwrap_or_else(|e| {
+        eprintln!("Battery history disabled: {e:#}");
+        None
+    });
+    let mut show_devices = false;
+    // Exit code of the most severe tier any battery is in
+    let mut exit_code = 0;
+
+    for reading in reader.read_all(&config).await {
+        let device = &reading.device;
+        match reading.address {
+            Some(address) if device.address.is_none() => println!(
+                "Reading battery for: {} ({address}, matched by {})",
+                device.name,
+                device.selector()
+            ),
+            Some(address) => println!("Reading battery for: {} ({address})", device.name),
+            None => println!(
+                "Reading battery for: {} ({})",
+                device.name,
+                device.selector()
+            ),
         }
-    };
 
-    let reader = ZmkBatteryReader::new().await?;
+        match reading.batteries {
+            Ok(batteries) => {
+                if batteries.is_empty() {
+                    println!("No battery levels reported");
+                    println!("Make sure:");
+                    println!("  1. The keyboard is connected");
+                    println!("  2. Battery reporting is enabled in ZMK firmware");
+                    println!("  3. The device address is correct in the config");
+                    println!(
+                        "\nConfig file location: {}",
+                        Config::config_path()?.display()
+                    );
+                } else {
+                    let options = device.read_options(&config.general);
+                    if let Some(address) = reading.address {
+                        if let Ok(info) = reader.read_device_info(address, &options).await {
+                            if !info.is_empty() {
+                                println!("{info}");
+                            }
+                        }
+                    }
 
-    println!("Reading battery for: {} ({})", device.name, device.address);
+                    let mut samples = Vec::new();
+                    if let Some(history) = history.as_mut() {
+                        let now = SystemTime::now();
+                        if let Err(e) =
+                            history.record_batteries(device, reading.address, &batteries, now)
+                        {
+                            eprintln!("Failed to record battery history: {e:#}");
+                        }
+                        let since = now.checked_sub(ESTIMATE_WINDOW).unwrap_or(now);
+                        samples = history
+                            .query(since.., Some(&device.name))
+                            .unwrap_or_default();
+                    }
 
-    match reader.read_battery_levels(&device.address).await {
-        Ok(batteries) => {
-            if batteries.is_empty() {
-                println!("No battery services found");
-                println!("Make sure:");
-                println!("  1. The keyboard is connected");
-                println!("  2. Battery reporting is enabled in ZMK firmware");
-                println!("  3. The device address is correct in the config");
-                println!(
-                    "\nConfig file location: {}",
-                    Config::config_path()?.display()
-                );
-            } else {
-                println!("\n=== Battery Levels ===");
-                for battery in batteries {
-                    println!("{}: {}%", battery.name, battery.level);
-
-                    // Check low battery threshold
-                    if battery.level <= device.low_battery_threshold {
-                        println!("  ⚠ Low battery warning!");
+                    println!("\n=== Battery Levels ===");
+                    for mut battery in batteries {
+                        let trend = LevelTrend::from_samples(
+                            samples.iter().filter(|s| s.battery == battery.name),
+                        );
+                        battery.charging = trend.state();
+                        let estimate =
+                            trend.estimate(device.warning_level(&battery, &config.tiers));
+                        let tier = device.tier(&battery, &config.tiers);
+                        if let Some(tier) = &tier {
+                            exit_code = exit_code.max(tier.exit_code);
+                        }
+                        print_battery(&battery, tier, estimate);
                     }
                 }
             }
-        }
-        Err(e) => {
-            eprintln!("Error reading battery levels: {e}");
-            eprintln!(
-                "\nConfig file location: {}",
-                Config::config_path()?.display()
-            );
-
-            // List available devices to help debug
-            println!("\nAvailable Bluetooth devices:");
-            if let Ok(devices) = reader.list_devices().await {
-                for (name, address) in devices {
-                    println!("  {name} - {address}");
+            Err(e) => {
+                eprintln!("Error reading battery levels: {e}");
+                if let Some(hint) = error_hint(&e) {
+                    eprintln!("{hint}");
                 }
+                eprintln!(
+                    "\nConfig file location: {}",
+                    Config::config_path()?.display()
+                );
+
+                show_devices |= matches!(
+                    e,
+                    ZmkError::NotPaired(_) | ZmkError::NoMatchingDevice(_) | ZmkError::DBus(_)
+                );
             }
         }
+        println!();
     }
 
+    // List available devices to help debug
+    if show_devices {
+        println!("Available keyboards:");
+        print_available_devices(&reader).await;
+    }
+
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
     Ok(())
 }
+
+fn print_battery(battery: &BatteryInfo, tier: Option<Tier>, estimate: Option<DischargeEstimate>) {
+    let mut notes = Vec::new();
+    if matches!(battery.charging, ChargeState::Charging | ChargeState::Full) {
+        notes.push(battery.charging.to_string());
+    }
+    if battery.origin == ReadOrigin::Cache {
+        notes.push("cached".to_string());
+    }
+    if battery.reconnected {
+        notes.push("reconnected".to_string());
+    }
+    if battery.retries > 0 {
+        notes.push(format!("after {} retries", battery.retries));
+    }
+    if notes.is_empty() {
+        println!("{}: {}%", battery.name, battery.level);
+    } else {
+        println!(
+            "{}: {}% ({})",
+            battery.name,
+            battery.level,
+            notes.join(", ")
+        );
+    }
+
+    if let Some(estimate) = estimate {
+        let mut line = format!("  {} left", format_remaining(estimate.until_empty));
+        if let Some(until_low) = estimate.until_low {
+            line.push_str(&format!(", low in {}", format_remaining(until_low)));
+        }
+        println!("{line} ({:.2}%/hour)", estimate.rate);
+    }
+
+    if let Some(tier) = tier {
+        println!("  ⚠ {} battery ({}% or less)", tier.label, tier.level);
+    }
+}
+
+async fn print_available_devices(backend: &dyn BatteryBackend) {
+    match backend.list_zmk_devices().await {
+        Ok(devices) if devices.is_empty() => {
+            println!("  No keyboards with battery reporting found");
+        }
+        Ok(devices) => {
+            for device in devices {
+                let status = if device.connected {
+                    "connected"
+                } else {
+                    "disconnected"
+                };
+                println!(
+                    "  {} - {} [{}, {status}]",
+                    device.display_name(),
+                    device.address,
+                    device.adapter
+                );
+            }
+        }
+        Err(_) => {}
+    }
+}
+
+fn error_hint(error: &ZmkError) -> Option<&'static str> {
+    match error {
+        ZmkError::BluezUnavailable => {
+            Some("Start the Bluetooth service: systemctl start bluetooth")
+        }
+        ZmkError::AdapterNotFound(_) => Some("Check the adapter name with: bluetoothctl list"),
+        ZmkError::NoMatchingDevice(_) => {
+            Some("Check match_name, match_alias or match_pattern against the list below")
+        }
+        ZmkError::NotPaired(_) => {
+            Some("Pair the keyboard with bluetoothctl, or check the address in the config")
+        }
+        ZmkError::Disconnected(_) => Some("Wake the keyboard up by pressing a key"),
+        ZmkError::ServicesNotResolved(_) => {
+            Some("The keyboard just connected; try again in a few seconds")
+        }
+        ZmkError::NoBatteryService(_) => {
+            Some("Enable battery reporting in the ZMK firmware (CONFIG_BT_BAS=y)")
+        }
+        ZmkError::PermissionDenied(_) => {
+            Some("Make sure your user may access BlueZ (e.g. is in the bluetooth group)")
+        }
+        ZmkError::Timeout(_) => {
+            Some("The keyboard did not answer; it may be asleep or out of range")
+        }
+        ZmkError::DBus(_) => None,
+    }
+}
@@ -0,0 +1,324 @@
+//! Prometheus metrics in the text exposition format.
+//!
+//! The metrics are rendered from the monitor's device status, either on
+//! request from a minimal HTTP listener or into a file for the node_exporter
+//! textfile collector. The file is replaced in one step so the collector
+//! never reads half of it.
+
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::DeviceStatus;
+
+/// Name of the file kept in the textfile collector directory
+pub const TEXTFILE_NAME: &str = "zmk_battery_monitor.prom";
+
+/// Largest request head read before giving up on a client
+const MAX_REQUEST: usize = 8192;
+/// Time a client has to send its request head before it is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause after failing to accept a connection, e.g. when out of file
+/// descriptors, before trying again
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
+
+/// Metrics of every device
+pub fn render(devices: &[DeviceStatus]) -> String {
+    let mut out = String::new();
+    let mut family = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{name}{{{labels}}} {value}");
+        }
+    };
+
+    family(
+        "zmk_battery_level",
+        "gauge",
+        "Battery level in percent.",
+        devices
+            .iter()
+            .flat_map(|state| {
+                state.batteries.iter().map(|battery| {
+                    (
+                        format!(
+                            "device=\"{}\",battery=\"{}\"",
+                            escape(&state.device.name),
+                            escape(&battery.name)
+                        ),
+                        battery.level.to_string(),
+                    )
+                })
+            })
+            .collect(),
+    );
+    family(
+        "zmk_device_connected",
+        "gauge",
+        "Whether the device is connected.",
+        devices
+            .iter()
+            .map(|state| (device_label(state), u8::from(state.connected).to_string()))
+            .collect(),
+    );
+    family(
+        "zmk_device_last_success_timestamp_seconds",
+        "gauge",
+        "Unix time of the last successful read or pushed update.",
+        devices
+            .iter()
+            .filter_map(|state| {
+                let elapsed = state.last_updated?.duration_since(UNIX_EPOCH).ok()?;
+                Some((device_label(state), elapsed.as_secs().to_string()))
+            })
+            .collect(),
+    );
+    family(
+        "zmk_read_errors_total",
+        "counter",
+        "Failed reads by kind of error.",
+        devices
+            .iter()
+            .flat_map(|state| {
+                state.read_errors.iter().map(|(kind, count)| {
+                    (
+                        format!("{},kind=\"{kind}\"", device_label(state)),
+                        count.to_string(),
+                    )
+                })
+            })
+            .collect(),
+    );
+    out
+}
+
+fn device_label(state: &DeviceStatus) -> String {
+    format!("device=\"{}\"", escape(&state.device.name))
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Write the metrics to `TEXTFILE_NAME` in `dir`
+pub fn write_textfile(dir: &Path, devices: &[DeviceStatus]) -> Result<()> {
+    let path = dir.join(TEXTFILE_NAME);
+    // The collector only reads *.prom files, so it skips the temporary one
+    let tmp = path.with_extension("prom.tmp");
+    fs::write(&tmp, render(devices))
+        .and_then(|()| fs::rename(&tmp, &path))
+        .with_context(|| format!("Failed to write metrics file: {}", path.display()))
+}
+
+/// Answer `GET /metrics` on `listener` for as long as the future is polled
+pub async fn serve(listener: TcpListener, devices: Arc<Mutex<Vec<DeviceStatus>>>) {
+    loop {
+        // Failing to accept one connection does not stop the endpoint
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept metrics connection: {e}");
+                tokio::time::sleep(ACCEPT_RETRY).await;
+                continue;
+            }
+        };
+        let devices = Arc::clone(&devices);
+        tokio::spawn(async move {
+            // A client going away early is its own problem
+            let _ = respond(stream, &devices).await;
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, devices: &Mutex<Vec<DeviceStatus>>) -> Result<()> {
+    // A client that never finishes its request would hold the connection
+    // open forever
+    let Ok(request) = tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut stream)).await else {
+        return Ok(());
+    };
+    let Some(request) = request? else {
+        return Ok(());
+    };
+
+    let response = response(&request, devices);
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Full HTTP response to a request with the head `request`
+fn response(request: &[u8], devices: &Mutex<Vec<DeviceStatus>>) -> String {
+    let line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut parts = std::str::from_utf8(line).unwrap_or_default().split(' ');
+    let (method, target) = (parts.next(), parts.next().unwrap_or_default());
+    let path = target.split('?').next().unwrap_or_default();
+    let (status, body) = match (method, path) {
+        (Some("GET" | "HEAD"), "/metrics") => ("200 OK", render(&devices.lock().unwrap())),
+        (Some("GET" | "HEAD"), _) => ("404 Not Found", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+    };
+    let mut response = format!(
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        body.len()
+    );
+    if method != Some("HEAD") {
+        response.push_str(&body);
+    }
+    response
+}
+
+/// Request line and headers, or `None` if the client closed the connection
+/// or sent too much
+async fn read_head(stream: &mut TcpStream) -> Result<Option<Vec<u8>>> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    Ok(Some(request))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BatteryInfo, BatterySource, ChargeState, Config, ReadOrigin};
+
+    /// A read keyboard whose names need escaping, and one not read yet
+    fn devices() -> Vec<DeviceStatus> {
+        let config: Config = toml::from_str(
+            r#"
+            [[devices]]
+            name = 'Corne "v4" \ left'
+            address = "AA:BB:CC:DD:EE:01"
+
+            [[devices]]
+            name = "Lily58"
+            address = "AA:BB:CC:DD:EE:02"
+            "#,
+        )
+        .unwrap();
+        let mut devices: Vec<_> = config
+            .devices
+            .iter()
+            .map(|device| DeviceStatus::new(device.clone(), device.read_options(&config.general)))
+            .collect();
+        devices[0].batteries = vec![BatteryInfo {
+            name: "Peripheral \"1\"".to_string(),
+            level: 42,
+            source: BatterySource::Peripheral(1),
+            retries: 0,
+            origin: ReadOrigin::Device,
+            reconnected: false,
+            charging: ChargeState::Discharging,
+        }];
+        devices[0].connected = true;
+        devices[0].last_updated = Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        devices[1].read_errors.insert("timeout", 3);
+        devices
+    }
+
+    #[test]
+    fn labels_are_escaped() {
+        assert_eq!(escape("Corne"), "Corne");
+        assert_eq!(escape(r#"say "hi""#), r#"say \"hi\""#);
+        assert_eq!(escape(r"a\b"), r"a\\b");
+        assert_eq!(escape("two\nlines"), r"two\nlines");
+    }
+
+    #[test]
+    fn exposition() {
+        assert_eq!(
+            render(&devices()),
+            r#"# HELP zmk_battery_level Battery level in percent.
+# TYPE zmk_battery_level gauge
+zmk_battery_level{device="Corne \"v4\" \\ left",battery="Peripheral \"1\""} 42
+# HELP zmk_device_connected Whether the device is connected.
+# TYPE zmk_device_connected gauge
+zmk_device_connected{device="Corne \"v4\" \\ left"} 1
+zmk_device_connected{device="Lily58"} 0
+# HELP zmk_device_last_success_timestamp_seconds Unix time of the last successful read or pushed update.
+# TYPE zmk_device_last_success_timestamp_seconds gauge
+zmk_device_last_success_timestamp_seconds{device="Corne \"v4\" \\ left"} 1700000000
+# HELP zmk_read_errors_total Failed reads by kind of error.
+# TYPE zmk_read_errors_total counter
+zmk_read_errors_total{device="Lily58",kind="timeout"} 3
+"#
+        );
+    }
+
+    #[test]
+    fn families_are_described_without_devices() {
+        let text = render(&[]);
+        assert_eq!(text.lines().count(), 8);
+        assert!(text.lines().all(|line| line.starts_with('#')));
+    }
+
+    fn respond_to(request: &str) -> String {
+        response(request.as_bytes(), &Mutex::new(devices()))
+    }
+
+    #[test]
+    fn metrics_are_served() {
+        let response = respond_to("GET /metrics?x=1 HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains("Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert_eq!(body, render(&devices()));
+    }
+
+    #[test]
+    fn head_has_no_body() {
+        let response = respond_to("HEAD /metrics HTTP/1.1\r\n\r\n");
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        let length = format!("Content-Length: {}\r\n", render(&devices()).len());
+        assert!(head.contains(&length));
+        assert_eq!(body, "");
+    }
+
+    #[test]
+    fn other_paths_are_not_found() {
+        for request in ["GET / HTTP/1.1\r\n\r\n", "HEAD /metricsx HTTP/1.1\r\n\r\n"] {
+            let response = respond_to(request);
+            assert!(
+                response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+                "{request}"
+            );
+        }
+        assert!(respond_to("GET / HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nNot found\n"));
+    }
+
+    #[test]
+    fn other_methods_are_not_allowed() {
+        for request in [
+            "POST /metrics HTTP/1.1\r\n\r\n",
+            "\r\n\r\n",
+            "\u{fffd}\r\n\r\n",
+        ] {
+            let response = respond_to(request);
+            assert!(
+                response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"),
+                "{request:?}"
+            );
+            assert!(response.ends_with("\r\n\r\nMethod not allowed\n"));
+        }
+    }
+}
@@ -0,0 +1,601 @@
+//! Long-running battery monitoring shared by the tray and the daemon.
+//!
+//! A `Monitor` polls every enabled device, keeps notification subscriptions
+//! alive, refreshes when a keyboard wakes up and starts over when BlueZ
+//! restarts. Consumers read the shared device status and react to the
+//! `MonitorEvent`s returned by `Monitor::next`, and ask for refreshes through
+//! a `RefreshHandle` so that `next` never has to be interrupted. Every level
+//! read or pushed is recorded if the monitor was given a `History`, and feeds
+//! the discharge estimate of its battery.
+
+use futures_util::stream::{self, BoxStream, SelectAll, StreamExt};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{Instant, Interval};
+
+use crate::config::{DeviceConfig, TierConfig};
+use crate::estimate::{ChargeEvent, DischargeEstimate, LevelTrend, ESTIMATE_WINDOW};
+use crate::{
+    sort_batteries, BackendState, BackendStateStream, BatteryBackend, BatteryInfo, BdAddr, Config,
+    DeviceEvent, DeviceEventStream, DeviceInformation, DeviceReading, History, ReadOptions, Sample,
+    ZmkError,
+};
+
+/// Last known state of one configured device
+#[derive(Debug)]
+pub struct DeviceStatus {
+    pub device: DeviceConfig,
+    /// Address the device resolved to, once known
+    pub address: Option<BdAddr>,
+    pub options: ReadOptions,
+    pub batteries: Vec<BatteryInfo>,
+    pub error: Option<ZmkError>,
+    /// The last read had to connect the keyboard first
+    pub reconnected: bool,
+    pub connected: bool,
+    pub details: Option<DeviceInformation>,
+    /// Time of the last successful read or pushed update
+    pub last_updated: Option<SystemTime>,
+    /// Failed reads since the monitor started, by `ZmkError::kind`
+    pub read_errors: BTreeMap<&'static str, u64>,
+    subscribed: bool,
+    /// Bumped on every subscription so that the end of a replaced stream
+    /// can be told apart from the end of the current one
+    generation: u64,
+    /// Dropping this ends the current notification stream
+    stop: Option<oneshot::Sender<()>>,
+    /// Level trends by battery name
+    trends: BTreeMap<String, LevelTrend>,
+}
+
+impl DeviceStatus {
+    pub(crate) fn new(device: DeviceConfig, options: ReadOptions) -> Self {
+        Self {
+            address: device.address,
+            device,
+            options,
+            batteries: Vec::new(),
+            error: None,
+            reconnected: false,
+            connected: false,
+            details: None,
+            last_updated: None,
+            read_errors: BTreeMap::new(),
+            subscribed: false,
+            generation: 0,
+            stop: None,
+            trends: BTreeMap::new(),
+        }
+    }
+
+    /// Predicted discharge of one of the device's batteries, once enough
+    /// levels were seen; time-to-low counts down to its "warning" tier as
+    /// resolved against the tier `definitions`
+    pub fn estimate(
+        &self,
+        battery: &BatteryInfo,
+        definitions: &BTreeMap<String, TierConfig>,
+    ) -> Option<DischargeEstimate> {
+        self.trends
+            .get(&battery.name)?
+            .estimate(self.device.warning_level(battery, definitions))
+    }
+
+    /// Feed an earlier level into the trend of its battery
+    fn seed_trend(&mut self, sample: &Sample) {
+        self.trends
+            .entry(sample.battery.clone())
+            .or_default()
+            .push(sample.time, sample.level);
+    }
+
+    /// Whether levels are pushed by the device rather than only polled
+    pub fn is_subscribed(&self) -> bool {
+        self.subscribed
+    }
+
+    /// Drop the current notification stream so the device is subscribed
+    /// again on the next attempt
+    fn unsubscribe(&mut self) {
+        self.subscribed = false;
+        self.stop = None;
+    }
+
+    /// Store a new level of device `index` seen at `time`, returning events
+    /// if it or the charge state changed
+    fn update_level(
+        &mut self,
+        index: usize,
+        mut battery: BatteryInfo,
+        time: SystemTime,
+    ) -> Vec<MonitorEvent> {
+        battery.name = self.device.battery_name(&battery);
+        let trend = self.trends.entry(battery.name.clone()).or_default();
+        let charge = trend.push(time, battery.level);
+        battery.charging = trend.state();
+
+        let previous = self
+            .batteries
+            .iter()
+            .find(|b| b.source == battery.source && b.name == battery.name)
+            .map(|b| b.level);
+        match self
+            .batteries
+            .iter_mut()
+            .find(|b| b.source == battery.source && b.name == battery.name)
+        {
+            Some(known) => {
+                known.level = battery.level;
+                known.charging = battery.charging;
+            }
+            None => {
+                self.batteries.push(battery.clone());
+                sort_batteries(&mut self.batteries);
+            }
+        }
+
+        let name = battery.name.clone();
+        let mut events = Vec::new();
+        if previous != Some(battery.level) {
+            events.push(MonitorEvent::LevelChanged {
+                device: index,
+                battery,
+                previous,
+            });
+        }
+        if let Some(event) = charge {
+            events.push(MonitorEvent::Charge {
+                device: index,
+                battery: name,
+                event,
+            });
+        }
+        events
+    }
+}
+
+/// Something that changed, as returned by `Monitor::next`.
+///
+/// `device` is an index into `Monitor::devices`.
+#[derive(Debug, Clone)]
+pub enum MonitorEvent {
+    /// Every device was read; their status may have changed in any way
+    Refreshed,
+    /// A battery level was read or pushed that differs from the last one
+    LevelChanged {
+        device: usize,
+        battery: BatteryInfo,
+        /// `None` for the first level of a battery
+        previous: Option<u8>,
+    },
+    /// A battery started charging or finished
+    Charge {
+        device: usize,
+        battery: String,
+        event: ChargeEvent,
+    },
+    /// A configured device connected, disconnected, ...
+    Device { device: usize, event: DeviceEvent },
+    /// BlueZ went away or came back
+    Backend(BackendState),
+    /// Levels could not be written to the history
+    HistoryFailed(String),
+}
+
+/// Pushed updates of all devices, tagged with the device index and
+/// subscription generation; `None` marks the end of a device's notifications
+type UpdateStream = SelectAll<BoxStream<'static, (usize, u64, Option<BatteryInfo>)>>;
+
+/// Refresh request, answered once the devices were read
+type RefreshRequest = oneshot::Sender<()>;
+
+/// Asks a `Monitor` to read all devices now, from anywhere
+#[derive(Debug, Clone)]
+pub struct RefreshHandle(mpsc::UnboundedSender<RefreshRequest>);
+
+impl RefreshHandle {
+    /// Ask for a refresh without waiting for it
+    pub fn request(&self) {
+        let (done, _) = oneshot::channel();
+        let _ = self.0.send(done);
+    }
+
+    /// Ask for a refresh and wait until it is done; `false` if the monitor
+    /// went away first
+    pub async fn refresh(&self) -> bool {
+        let (done, wait) = oneshot::channel();
+        self.0.send(done).is_ok() && wait.await.is_ok()
+    }
+}
+
+/// What woke up `Monitor::next`
+enum Wake {
+    Refresh(RefreshRequest),
+    Update(usize, u64, Option<BatteryInfo>),
+    Event(Option<DeviceEvent>),
+    Backend(Option<BackendState>),
+    Tick,
+}
+
+pub struct Monitor {
+    backend: Box<dyn BatteryBackend>,
+    config: Config,
+    devices: Arc<Mutex<Vec<DeviceStatus>>>,
+    updates: UpdateStream,
+    events: Option<DeviceEventStream>,
+    backend_states: Option<BackendStateStream>,
+    interval: Interval,
+    pending: VecDeque<MonitorEvent>,
+    history: Option<History>,
+    refresh_requests: mpsc::UnboundedReceiver<RefreshRequest>,
+    /// Kept so that `refresh_requests` never ends
+    refresh_handle: RefreshHandle,
+}
+
+impl Monitor {
+    /// Read every enabled device of `config` once and start watching them.
+    ///
+    /// Devices are polled every `general.update_interval` seconds. Pushed
+    /// updates, device events and BlueZ restarts are followed where the
+    /// backend supports them; otherwise polling carries on alone.
+    pub async fn start(backend: Box<dyn BatteryBackend>, config: Config) -> Self {
+        Self::start_with_history(backend, config, None).await
+    }
+
+    /// Like `start`, recording every level into `history`
+    pub async fn start_with_history(
+        backend: Box<dyn BatteryBackend>,
+        config: Config,
+        history: Option<History>,
+    ) -> Self {
+        let mut devices: Vec<_> = config
+            .get_enabled_devices()
+            .into_iter()
+            .map(|device| DeviceStatus::new(device.clone(), device.read_options(&config.general)))
+            .collect();
+
+        // Pick up the trends where the last run left off
+        if let Some(history) = &history {
+            let since = SystemTime::now()
+                .checked_sub(ESTIMATE_WINDOW)
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            for sample in history.query(since.., None).unwrap_or_default() {
+                if let Some(state) = devices.iter_mut().find(|s| s.device.name == sample.device) {
+                    state.seed_trend(&sample);
+                }
+            }
+        }
+
+        let period = Duration::from_secs(config.general.update_interval.max(1));
+        let (refresh_handle, refresh_requests) = mpsc::unbounded_channel();
+        let mut monitor = Self {
+            events: backend.device_events().await.ok(),
+            // The first state is the current one
+            backend_states: backend
+                .backend_states()
+                .await
+                .ok()
+                .map(|states| states.skip(1).boxed()),
+            backend,
+            config,
+            devices: Arc::new(Mutex::new(devices)),
+            updates: UpdateStream::new(),
+            interval: tokio::time::interval_at(Instant::now() + period, period),
+            pending: VecDeque::new(),
+            history,
+            refresh_requests,
+            refresh_handle: RefreshHandle(refresh_handle),
+        };
+        monitor.refresh().await;
+        monitor
+    }
+
+    /// Status of every enabled device, in config order
+    pub fn devices(&self) -> Arc<Mutex<Vec<DeviceStatus>>> {
+        Arc::clone(&self.devices)
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Handle for asking this monitor to refresh while something waits in
+    /// `next`
+    pub fn refresh_handle(&self) -> RefreshHandle {
+        self.refresh_handle.clone()
+    }
+
+    /// Read all devices now, fetch missing device details and subscribe to
+    /// notifications where possible
+    pub async fn refresh(&mut self) {
+        let readings = self.backend.read_all(&self.config).await;
+        let now = SystemTime::now();
+        let mut samples = Vec::new();
+
+        {
+            let mut devices = self.devices.lock().unwrap();
+            for (i, (state, reading)) in devices.iter_mut().zip(readings).enumerate() {
+                let DeviceReading {
+                    address, batteries, ..
+                } = reading;
+                if address.is_some() && address != state.address {
+                    // Matched by name and re-paired under a new address
+                    state.address = address;
+                    state.details = None;
+                    state.unsubscribe();
+                }
+                match batteries {
+                    Ok(batteries) => {
+                        state.reconnected = batteries.iter().any(|b| b.reconnected);
+                        state.connected = true;
+                        state.error = None;
+                        state.last_updated = Some(now);
+
+                        let mut current = Vec::new();
+                        for battery in batteries {
+                            current.push((battery.source, battery.name.clone()));
+                            self.pending.extend(state.update_level(i, battery, now));
+                        }
+                        // Drop batteries the device no longer reports
+                        state
+                            .batteries
+                            .retain(|b| current.contains(&(b.source, b.name.clone())));
+                        samples.extend(
+                            state
+                                .batteries
+                                .iter()
+                                .map(|b| Sample::new(&state.device, state.address, b, now)),
+                        );
+                    }
+                    Err(e) => {
+                        state.batteries.clear();
+                        state.reconnected = false;
+                        if matches!(
+                            e,
+                            ZmkError::Disconnected(_)
+                                | ZmkError::NotPaired(_)
+                                | ZmkError::NoMatchingDevice(_)
+                                | ZmkError::BluezUnavailable
+                        ) {
+                            state.connected = false;
+                        }
+                        *state.read_errors.entry(e.kind()).or_default() += 1;
+                        state.error = Some(e);
+                    }
+                }
+            }
+        }
+
+        self.record(&samples);
+        self.update_details().await;
+        self.subscribe().await;
+        self.pending.push_back(MonitorEvent::Refreshed);
+    }
+
+    /// Wait for the next change.
+    ///
+    /// Polls, follows notifications and device events in the meantime, and
+    /// refreshes when asked to through a `RefreshHandle`. The device status is
+    /// up to date when an event is returned.
+    ///
+    /// Not cancel safe: dropping the future can abandon a refresh halfway.
+    /// Rather than racing it against other futures in `select!`, send the
+    /// work it should do through a `RefreshHandle`.
+    pub async fn next(&mut self) -> MonitorEvent {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return event;
+            }
+
+            let wake = {
+                let Self {
+                    updates,
+                    events,
+                    backend_states,
+                    interval,
+                    refresh_requests,
+                    ..
+                } = self;
+                tokio::select! {
+                    Some(done) = refresh_requests.recv() => Wake::Refresh(done),
+                    Some((i, generation, update)) = updates.next(), if !updates.is_empty() => {
+                        Wake::Update(i, generation, update)
+                    }
+                    event = async { events.as_mut()?.next().await }, if events.is_some() => {
+                        Wake::Event(event)
+                    }
+                    state = async { backend_states.as_mut()?.next().await }, if backend_states.is_some() => {
+                        Wake::Backend(state)
+                    }
+                    _ = interval.tick() => Wake::Tick,
+                }
+            };
+
+            match wake {
+                Wake::Refresh(done) => {
+                    // Answer requests that piled up meanwhile with the same read
+                    let mut waiting = vec![done];
+                    while let Ok(done) = self.refresh_requests.try_recv() {
+                        waiting.push(done);
+                    }
+                    self.refresh().await;
+                    for done in waiting {
+                        let _ = done.send(());
+                    }
+                }
+                Wake::Update(i, generation, update) => {
+                    let sample = {
+                        let mut devices = self.devices.lock().unwrap();
+                        let state = &mut devices[i];
+                        match update {
+                            Some(update) => {
+                                let now = SystemTime::now();
+                                state.connected = true;
+                                state.error = None;
+                                state.last_updated = Some(now);
+                                let events = state.update_level(i, update, now);
+                                let sample = events.iter().find_map(|event| match event {
+                                    MonitorEvent::LevelChanged { battery, .. } => Some(
+                                        Sample::new(&state.device, state.address, battery, now),
+                                    ),
+                                    _ => None,
+                                });
+                                self.pending.extend(events);
+                                sample
+                            }
+                            // Notifications stopped, e.g. the keyboard disconnected
+                            None if state.generation == generation => {
+                                state.unsubscribe();
+                                None
+                            }
+                            None => None,
+                        }
+                    };
+                    if let Some(sample) = sample {
+                        self.record(&[sample]);
+                    }
+                }
+                Wake::Event(Some(event)) => self.handle_device_event(event).await,
+                Wake::Event(None) => self.events = None,
+                Wake::Backend(Some(BackendState::Unavailable)) => {
+                    self.reset();
+                    self.pending
+                        .push_back(MonitorEvent::Backend(BackendState::Unavailable));
+                }
+                Wake::Backend(Some(BackendState::Available)) => {
+                    self.pending
+                        .push_back(MonitorEvent::Backend(BackendState::Available));
+                    self.events = self.backend.device_events().await.ok();
+                    self.refresh().await;
+                }
+                Wake::Backend(None) => self.backend_states = None,
+                Wake::Tick => self.refresh().await,
+            }
+        }
+    }
+
+    async fn handle_device_event(&mut self, event: DeviceEvent) {
+        let affected: Vec<_> = {
+            let mut devices = self.devices.lock().unwrap();
+            devices
+                .iter_mut()
+                .enumerate()
+                .filter(|(_, state)| state.address == Some(event.address()))
+                .map(|(i, state)| {
+                    // BlueZ forgets notification state on disconnect
+                    state.unsubscribe();
+                    match event {
+                        DeviceEvent::Connected { .. } | DeviceEvent::ServicesResolved { .. } => {
+                            state.connected = true
+                        }
+                        DeviceEvent::Disconnected { .. } | DeviceEvent::Removed { .. } => {
+                            state.connected = false
+                        }
+                        DeviceEvent::BatteryServiceAppeared { .. } => {}
+                    }
+                    i
+                })
+                .collect()
+        };
+
+        for &device in &affected {
+            self.pending.push_back(MonitorEvent::Device {
+                device,
+                event: event.clone(),
+            });
+        }
+        if !affected.is_empty() && is_wake_event(&event) {
+            self.refresh().await;
+        }
+    }
+
+    /// Append samples to the history, if any; failures are reported as an
+    /// event rather than interrupting monitoring
+    fn record(&mut self, samples: &[Sample]) {
+        let Some(history) = self.history.as_mut() else {
+            return;
+        };
+        if let Err(e) = history.record(samples) {
+            self.pending
+                .push_back(MonitorEvent::HistoryFailed(format!("{e:#}")));
+        }
+    }
+
+    /// Forget everything learned from a BlueZ instance that went away
+    fn reset(&mut self) {
+        for state in self.devices.lock().unwrap().iter_mut() {
+            state.unsubscribe();
+            state.batteries.clear();
+            state.connected = false;
+            state.error = Some(ZmkError::BluezUnavailable);
+        }
+    }
+
+    /// Read the Device Information Service of devices that do not have it yet
+    async fn update_details(&mut self) {
+        let missing: Vec<_> = {
+            let devices = self.devices.lock().unwrap();
+            devices
+                .iter()
+                .enumerate()
+                .filter(|(_, state)| state.details.is_none())
+                .filter_map(|(i, state)| Some((i, state.address?, state.options.clone())))
+                .collect()
+        };
+
+        for (i, address, options) in missing {
+            if let Ok(info) = self.backend.read_device_info(address, &options).await {
+                self.devices.lock().unwrap()[i].details = Some(info);
+            }
+        }
+    }
+
+    /// Subscribe to notifications of devices that are not subscribed yet
+    async fn subscribe(&mut self) {
+        let pending: Vec<_> = {
+            let devices = self.devices.lock().unwrap();
+            devices
+                .iter()
+                .enumerate()
+                .filter(|(_, state)| !state.subscribed)
+                .filter_map(|(i, state)| Some((i, state.address?, state.options.clone())))
+                .collect()
+        };
+
+        for (i, address, options) in pending {
+            let Ok(stream) = self
+                .backend
+                .subscribe_battery_levels(address, &options)
+                .await
+            else {
+                continue;
+            };
+
+            let (stop, stopped) = oneshot::channel();
+            let mut devices = self.devices.lock().unwrap();
+            let state = &mut devices[i];
+            state.generation += 1;
+            state.subscribed = true;
+            state.stop = Some(stop);
+
+            let generation = state.generation;
+            let tagged = stream
+                .take_until(stopped)
+                .map(move |update| (i, generation, Some(update)))
+                .chain(stream::once(async move { (i, generation, None) }));
+            self.updates.push(tagged.boxed());
+        }
+    }
+}
+
+/// Whether a device event means the keyboard can be read again
+fn is_wake_event(event: &DeviceEvent) -> bool {
+    matches!(
+        event,
+        DeviceEvent::Connected { .. }
+            | DeviceEvent::ServicesResolved { .. }
+            | DeviceEvent::BatteryServiceAppeared { .. }
+    )
+}
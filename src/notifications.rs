@@ -0,0 +1,460 @@
+//! Desktop notifications through `org.freedesktop.Notifications`.
+//!
+//! A battery is reported once when it drops into a threshold tier, again
+//! when it drops into a more severe one, and otherwise only every
+//! `repeat_interval_minutes` while it stays low and is not charging. A tier
+//! is left once the level climbs `hysteresis` points above it, so a level
+//! bouncing between 19% and 20% notifies only once. Leaving the last tier
+//! is reported as recovered.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Instant;
+use zbus::zvariant::Value;
+use zbus::Connection;
+
+use crate::config::{NotificationConfig, Tier, TierConfig, Urgency};
+use crate::estimate::format_remaining;
+use crate::{BatteryInfo, ChargeEvent, ChargeState, Config, DeviceStatus, MonitorEvent, Result};
+
+/// Something to tell the user about one battery
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Alert {
+    /// The level dropped into `tier`, or is still in it after the repeat
+    /// interval
+    Low { level: u8, tier: Tier },
+    /// The level climbed back out of every tier
+    Recovered { level: u8 },
+}
+
+/// Low battery state of every battery, without sending anything
+#[derive(Debug, Default)]
+pub struct LowBatteryTracker {
+    /// Current tier and last low notification of batteries that are low, by
+    /// device index and battery name
+    low: HashMap<(usize, String), (Tier, Instant)>,
+}
+
+impl LowBatteryTracker {
+    /// Feed the current level of a battery with its `tiers`, most severe
+    /// first, returning an alert if one is due
+    pub fn update(
+        &mut self,
+        config: &NotificationConfig,
+        device: usize,
+        battery: &BatteryInfo,
+        tiers: &[Tier],
+        now: Instant,
+    ) -> Option<Alert> {
+        let key = (device, battery.name.clone());
+        let level = battery.level;
+        // No point nagging about a battery that is plugged in
+        let charging = matches!(battery.charging, ChargeState::Charging | ChargeState::Full);
+        let low = |tier: &Tier| {
+            (!charging).then(|| Alert::Low {
+                level,
+                tier: tier.clone(),
+            })
+        };
+
+        let entered = tiers.iter().find(|tier| level <= tier.level);
+        // The tier the battery is still in when allowing for hysteresis
+        let held = tiers
+            .iter()
+            .find(|tier| level < tier.level.saturating_add(config.hysteresis.max(1)));
+
+        let Some((current, last)) = self.low.get_mut(&key) else {
+            let tier = entered?;
+            self.low.insert(key, (tier.clone(), now));
+            return low(tier);
+        };
+        match (entered, held) {
+            (Some(tier), _) if tier.level < current.level => {
+                *current = tier.clone();
+                *last = now;
+                low(tier)
+            }
+            (_, None) => {
+                self.low.remove(&key);
+                config
+                    .notify_recovered
+                    .then_some(Alert::Recovered { level })
+            }
+            (_, Some(tier)) if tier.level > current.level => {
+                // Climbed into a milder tier; nothing worth a notification
+                *current = tier.clone();
+                None
+            }
+            _ => {
+                let due = config
+                    .repeat_interval()
+                    .is_some_and(|interval| now.duration_since(*last) >= interval);
+                if !due || level > current.level {
+                    return None;
+                }
+                *last = now;
+                low(current)
+            }
+        }
+    }
+}
+
+/// Sends notifications for the events of a `Monitor`
+pub struct Notifier {
+    conn: Connection,
+    config: NotificationConfig,
+    tiers: BTreeMap<String, TierConfig>,
+    tracker: LowBatteryTracker,
+    /// Id of the last notification per battery, so that a new one replaces it
+    ids: HashMap<(usize, String), u32>,
+}
+
+struct Notification {
+    key: (usize, String),
+    summary: String,
+    body: String,
+    icon: &'static str,
+    /// 0 low, 1 normal, 2 critical
+    urgency: u8,
+}
+
+impl Notifier {
+    /// Connect to the notification service on the session bus, notifying
+    /// as set in `[notifications]` for the tiers of `config`
+    pub async fn new(config: &Config) -> Result<Self> {
+        Ok(Self {
+            conn: Connection::session().await?,
+            config: config.notifications.clone(),
+            tiers: config.tiers.clone(),
+            tracker: LowBatteryTracker::default(),
+            ids: HashMap::new(),
+        })
+    }
+
+    /// Notify about whatever `event` calls for; `devices` is the monitor's
+    /// device status
+    pub async fn handle(
+        &mut self,
+        event: &MonitorEvent,
+        devices: &Mutex<Vec<DeviceStatus>>,
+    ) -> Result<()> {
+        let notifications = {
+            let devices = devices.lock().unwrap();
+            self.notifications(event, &devices)
+        };
+        for notification in notifications {
+            self.send(notification).await?;
+        }
+        Ok(())
+    }
+
+    fn notifications(
+        &mut self,
+        event: &MonitorEvent,
+        devices: &[DeviceStatus],
+    ) -> Vec<Notification> {
+        let now = Instant::now();
+        let mut notifications = Vec::new();
+        let mut check = |tracker: &mut LowBatteryTracker, i: usize, battery: &BatteryInfo| {
+            let state = &devices[i];
+            let tiers = state.device.tiers(battery, &self.tiers);
+            let alert = tracker.update(&self.config, i, battery, &tiers, now);
+            notifications
+                .extend(alert.map(|alert| low_battery(state, &self.tiers, i, battery, alert)));
+        };
+
+        match event {
+            MonitorEvent::LevelChanged {
+                device, battery, ..
+            } => {
+                if let Some(battery) = devices[*device]
+                    .batteries
+                    .iter()
+                    .find(|b| b.name == battery.name)
+                {
+                    check(&mut self.tracker, *device, battery);
+                }
+            }
+            // Every device was read, so repeats fall due here
+            MonitorEvent::Refreshed => {
+                for (i, state) in devices.iter().enumerate() {
+                    for battery in &state.batteries {
+                        check(&mut self.tracker, i, battery);
+                    }
+                }
+            }
+            MonitorEvent::Charge {
+                device,
+                battery,
+                event: ChargeEvent::Charged { level },
+            } if self.config.notify_charged => {
+                let name = &devices[*device].device.name;
+                let body = if *level >= 100 {
+                    "Fully charged, safe to unplug".to_string()
+                } else {
+                    format!("Charged to {level}%")
+                };
+                notifications.push(Notification {
+                    key: (*device, battery.clone()),
+                    summary: format!("{name}: {battery} charged"),
+                    body,
+                    icon: "battery-full-charged",
+                    urgency: 1,
+                });
+            }
+            _ => {}
+        }
+        notifications
+    }
+
+    async fn send(&mut self, notification: Notification) -> Result<()> {
+        let proxy = zbus::Proxy::new(
+            &self.conn,
+            "org.freedesktop.Notifications",
+            "/org/freedesktop/Notifications",
+            "org.freedesktop.Notifications",
+        )
+        .await?;
+        let replaces_id = self.ids.get(&notification.key).copied().unwrap_or(0);
+        let hints = HashMap::from([("urgency", Value::from(notification.urgency))]);
+        let id: u32 = proxy
+            .call(
+                "Notify",
+                &(
+                    "ZMK Battery Monitor",
+                    replaces_id,
+                    notification.icon,
+                    notification.summary.as_str(),
+                    notification.body.as_str(),
+                    Vec::<&str>::new(),
+                    hints,
+                    -1i32,
+                ),
+            )
+            .await?;
+        self.ids.insert(notification.key, id);
+        Ok(())
+    }
+}
+
+fn low_battery(
+    state: &DeviceStatus,
+    tiers: &BTreeMap<String, TierConfig>,
+    device: usize,
+    battery: &BatteryInfo,
+    alert: Alert,
+) -> Notification {
+    let key = (device, battery.name.clone());
+    let name = &state.device.name;
+    match alert {
+        Alert::Low { level, tier } => {
+            let mut body = format!("{level}% remaining");
+            if let Some(estimate) = state.estimate(battery, tiers) {
+                body.push_str(&format!(
+                    ", {} left",
+                    format_remaining(estimate.until_empty)
+                ));
+            }
+            Notification {
+                key,
+                summary: format!(
+                    "{name}: {} battery {}",
+                    battery.name,
+                    tier.label.to_lowercase()
+                ),
+                body,
+                icon: match tier.urgency {
+                    Urgency::Critical => "battery-empty",
+                    Urgency::Low | Urgency::Normal => "battery-caution",
+                },
+                urgency: tier.urgency as u8,
+            }
+        }
+        Alert::Recovered { level } => Notification {
+            key,
+            summary: format!("{name}: {} battery recovered", battery.name),
+            body: format!("Now at {level}%"),
+            icon: "battery-good",
+            urgency: 1,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BatterySource, ReadOrigin};
+    use std::time::Duration;
+
+    fn tier(name: &str, level: u8, urgency: Urgency) -> Tier {
+        Tier {
+            name: name.to_string(),
+            level,
+            urgency,
+            exit_code: 1,
+            label: name.to_string(),
+        }
+    }
+
+    /// Central battery of device 0 with critical at 10% and warning at 20%
+    struct Battery {
+        tracker: LowBatteryTracker,
+        config: NotificationConfig,
+        tiers: Vec<Tier>,
+        start: Instant,
+    }
+
+    impl Battery {
+        fn new(config: NotificationConfig) -> Self {
+            Self {
+                tracker: LowBatteryTracker::default(),
+                config,
+                tiers: vec![
+                    tier("critical", 10, Urgency::Critical),
+                    tier("warning", 20, Urgency::Normal),
+                ],
+                start: Instant::now(),
+            }
+        }
+
+        fn level(&mut self, minutes: u64, level: u8, charging: ChargeState) -> Option<Alert> {
+            let battery = BatteryInfo {
+                name: "Central".to_string(),
+                level,
+                source: BatterySource::Central,
+                retries: 0,
+                origin: ReadOrigin::Device,
+                reconnected: false,
+                charging,
+            };
+            let now = self.start + Duration::from_secs(minutes * 60);
+            self.tracker
+                .update(&self.config, 0, &battery, &self.tiers, now)
+        }
+
+        /// Alerts for discharging `levels`, one a minute
+        fn levels(&mut self, levels: &[u8]) -> Vec<Option<Alert>> {
+            levels
+                .iter()
+                .enumerate()
+                .map(|(i, &level)| self.level(i as u64, level, ChargeState::Discharging))
+                .collect()
+        }
+
+        fn low(&self, level: u8, tier: usize) -> Option<Alert> {
+            Some(Alert::Low {
+                level,
+                tier: self.tiers[tier].clone(),
+            })
+        }
+    }
+
+    const CRITICAL: usize = 0;
+    const WARNING: usize = 1;
+
+    #[test]
+    fn bouncing_at_the_threshold_notifies_once() {
+        let mut battery = Battery::new(NotificationConfig::default());
+        let alerts = battery.levels(&[21, 20, 21, 20, 19, 20, 21]);
+        let low = battery.low(20, WARNING);
+        assert_eq!(alerts, [None, low, None, None, None, None, None]);
+    }
+
+    #[test]
+    fn recovery_needs_the_hysteresis() {
+        let mut battery = Battery::new(NotificationConfig::default());
+        let alerts = battery.levels(&[20, 24, 23, 25, 20]);
+        assert_eq!(
+            alerts,
+            [
+                battery.low(20, WARNING),
+                None,
+                None,
+                Some(Alert::Recovered { level: 25 }),
+                battery.low(20, WARNING),
+            ]
+        );
+
+        let mut battery = Battery::new(NotificationConfig {
+            notify_recovered: false,
+            ..Default::default()
+        });
+        // Quietly recovered, so dropping again notifies
+        let alerts = battery.levels(&[20, 25, 20]);
+        let low = battery.low(20, WARNING);
+        assert_eq!(alerts, [low.clone(), None, low]);
+    }
+
+    #[test]
+    fn escalates_to_more_severe_tiers() {
+        let mut battery = Battery::new(NotificationConfig::default());
+        let alerts = battery.levels(&[20, 15, 10, 9, 12, 15, 10]);
+        assert_eq!(
+            alerts,
+            [
+                battery.low(20, WARNING),
+                None,
+                battery.low(10, CRITICAL),
+                None,
+                // Within the hysteresis of critical
+                None,
+                // Back to warning, quietly
+                None,
+                battery.low(10, CRITICAL),
+            ]
+        );
+    }
+
+    #[test]
+    fn skipping_a_tier_reports_the_most_severe() {
+        let mut battery = Battery::new(NotificationConfig::default());
+        assert_eq!(battery.levels(&[50, 8]), [None, battery.low(8, CRITICAL)]);
+    }
+
+    #[test]
+    fn reminds_after_the_repeat_interval() {
+        let mut battery = Battery::new(NotificationConfig::default());
+        let discharging = ChargeState::Discharging;
+        assert_eq!(battery.level(0, 20, discharging), battery.low(20, WARNING));
+        assert_eq!(battery.level(30, 19, discharging), None);
+        assert_eq!(battery.level(60, 19, discharging), battery.low(19, WARNING));
+        assert_eq!(battery.level(90, 18, discharging), None);
+        assert_eq!(
+            battery.level(120, 18, discharging),
+            battery.low(18, WARNING)
+        );
+        // Above the threshold, within the hysteresis: no reminder
+        assert_eq!(battery.level(240, 22, discharging), None);
+    }
+
+    #[test]
+    fn no_reminders_without_a_repeat_interval() {
+        let mut battery = Battery::new(NotificationConfig {
+            repeat_interval_minutes: 0,
+            ..Default::default()
+        });
+        let discharging = ChargeState::Discharging;
+        assert_eq!(battery.level(0, 20, discharging), battery.low(20, WARNING));
+        assert_eq!(battery.level(600, 19, discharging), None);
+    }
+
+    #[test]
+    fn no_reminders_while_charging() {
+        let mut battery = Battery::new(NotificationConfig::default());
+        assert_eq!(
+            battery.level(0, 20, ChargeState::Discharging),
+            battery.low(20, WARNING)
+        );
+        assert_eq!(battery.level(60, 19, ChargeState::Charging), None);
+        assert_eq!(battery.level(120, 20, ChargeState::Charging), None);
+
+        // Plugged in before it got low: quiet, but recovery still counts
+        let mut battery = Battery::new(NotificationConfig::default());
+        assert_eq!(battery.level(0, 20, ChargeState::Charging), None);
+        assert_eq!(battery.level(10, 8, ChargeState::Charging), None);
+        assert_eq!(
+            battery.level(20, 30, ChargeState::Charging),
+            Some(Alert::Recovered { level: 30 })
+        );
+    }
+}
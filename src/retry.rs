@@ -0,0 +1,185 @@
+use std::future::Future;
+use std::time::Duration;
+
+use crate::{Result, ZmkError};
+
+/// Timeout and retry settings for a single D-Bus call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Time allowed for each attempt
+    pub timeout: Duration,
+    /// Retries after the first attempt
+    pub max_retries: u32,
+    /// Delay before the first retry; doubled for every further retry
+    pub initial_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(250),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Run `op` until it succeeds, fails with a non-retryable error, or runs
+    /// out of retries. Returns the value and the number of retries taken.
+    pub async fn run<T, F, Fut>(&self, mut op: F) -> Result<(T, u32)>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut backoff = self.initial_backoff;
+        let mut retries = 0;
+
+        loop {
+            let result = match tokio::time::timeout(self.timeout, op()).await {
+                Ok(result) => result,
+                Err(_) => Err(ZmkError::Timeout(self.timeout)),
+            };
+
+            match result {
+                Err(e) if e.is_retryable() && retries < self.max_retries => {
+                    tokio::time::sleep(backoff).await;
+                    backoff = backoff.saturating_mul(2);
+                    retries += 1;
+                }
+                result => return result.map(|value| (value, retries)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BdAddr;
+    use std::cell::Cell;
+    use tokio::time::Instant;
+    use zbus::names::OwnedErrorName;
+    use zbus::Message;
+
+    fn method_error(name: &str) -> ZmkError {
+        let message = Message::method("/", "ReadValue")
+            .unwrap()
+            .build(&())
+            .unwrap();
+        ZmkError::DBus(zbus::Error::MethodError(
+            OwnedErrorName::try_from(name).unwrap(),
+            None,
+            message,
+        ))
+    }
+
+    /// Run `policy` over an operation failing with `error` `failures` times,
+    /// returning the result, the attempts made and the time it took
+    async fn run(
+        policy: RetryPolicy,
+        failures: u32,
+        error: impl Fn() -> ZmkError,
+    ) -> (Result<(&'static str, u32)>, u32, Duration) {
+        let attempts = Cell::new(0);
+        let start = Instant::now();
+        let result = policy
+            .run(|| {
+                attempts.set(attempts.get() + 1);
+                let result = if attempts.get() <= failures {
+                    Err(error())
+                } else {
+                    Ok("value")
+                };
+                async { result }
+            })
+            .await;
+        (result, attempts.get(), start.elapsed())
+    }
+
+    fn in_progress() -> ZmkError {
+        method_error("org.bluez.Error.InProgress")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_with_backoff() {
+        let (result, attempts, elapsed) = run(RetryPolicy::default(), 2, in_progress).await;
+        assert_eq!(result.unwrap(), ("value", 2));
+        assert_eq!(attempts, 3);
+        // 250 ms, then doubled
+        assert_eq!(elapsed, Duration::from_millis(250 + 500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn first_attempt_needs_no_retries() {
+        let (result, attempts, elapsed) = run(RetryPolicy::default(), 0, in_progress).await;
+        assert_eq!(result.unwrap(), ("value", 0));
+        assert_eq!(attempts, 1);
+        assert_eq!(elapsed, Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_max_retries() {
+        let (result, attempts, elapsed) = run(RetryPolicy::default(), 10, in_progress).await;
+        assert!(matches!(result, Err(ZmkError::DBus(_))));
+        assert_eq!(attempts, 4);
+        assert_eq!(elapsed, Duration::from_millis(250 + 500 + 1000));
+
+        let policy = RetryPolicy {
+            max_retries: 0,
+            ..Default::default()
+        };
+        let (result, attempts, _) = run(policy, 1, in_progress).await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn other_errors_are_returned_at_once() {
+        for error in [
+            || ZmkError::Disconnected(BdAddr::new([1, 2, 3, 4, 5, 6])),
+            || ZmkError::NoMatchingDevice("Corne".to_string()),
+            || method_error("org.bluez.Error.NotPermitted"),
+        ] {
+            let (result, attempts, elapsed) = run(RetryPolicy::default(), 1, error).await;
+            assert!(result.is_err());
+            assert_eq!(attempts, 1, "{:?}", error());
+            assert_eq!(elapsed, Duration::ZERO);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn attempts_that_hang_time_out_and_are_retried() {
+        let attempts = Cell::new(0);
+        let start = Instant::now();
+        let result = RetryPolicy::default()
+            .run(|| {
+                attempts.set(attempts.get() + 1);
+                let hang = attempts.get() == 1;
+                async move {
+                    if hang {
+                        std::future::pending::<()>().await;
+                    }
+                    Ok(())
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), ((), 1));
+        assert_eq!(start.elapsed(), Duration::from_millis(5000 + 250));
+    }
+
+    #[test]
+    fn retryable_errors() {
+        for (name, retryable) in [
+            ("org.bluez.Error.InProgress", true),
+            ("org.bluez.Error.NotReady", true),
+            ("org.bluez.Error.Failed", true),
+            ("org.freedesktop.DBus.Error.NoReply", true),
+            ("org.bluez.Error.NotPermitted", false),
+            ("org.bluez.Error.NotConnected", false),
+        ] {
+            assert_eq!(method_error(name).is_retryable(), retryable, "{name}");
+        }
+        assert!(ZmkError::Timeout(Duration::from_secs(5)).is_retryable());
+    }
+}
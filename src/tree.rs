@@ -0,0 +1,491 @@
+//! Typed view of a BlueZ `GetManagedObjects` snapshot.
+//!
+//! Parsing is pure so that battery discovery works on plain lookups and can
+//! be fed recorded snapshots.
+
+use std::collections::HashMap;
+use zbus::zvariant::{OwnedObjectPath, OwnedValue};
+
+use crate::{BdAddr, Result, BATTERY_UUID};
+
+/// Properties of one D-Bus interface
+pub type Properties = HashMap<String, OwnedValue>;
+
+/// Reply of `org.freedesktop.DBus.ObjectManager.GetManagedObjects`
+pub type ManagedObjects = HashMap<OwnedObjectPath, HashMap<String, Properties>>;
+
+#[derive(Debug, Clone, Default)]
+pub struct ObjectTree {
+    pub adapters: Vec<Adapter>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Adapter {
+    pub path: String,
+    /// Last path segment, e.g. `hci0`
+    pub name: String,
+    pub address: Option<BdAddr>,
+    pub devices: Vec<Device>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Device {
+    pub path: String,
+    pub adapter: String,
+    pub address: BdAddr,
+    pub name: Option<String>,
+    pub alias: Option<String>,
+    pub paired: bool,
+    pub bonded: bool,
+    pub connected: bool,
+    pub trusted: bool,
+    pub services_resolved: bool,
+    pub icon: Option<String>,
+    pub appearance: Option<u16>,
+    pub rssi: Option<i16>,
+    pub uuids: Vec<String>,
+    pub services: Vec<Service>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Service {
+    pub path: String,
+    pub uuid: String,
+    pub characteristics: Vec<Characteristic>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Characteristic {
+    pub path: String,
+    pub uuid: String,
+    pub flags: Vec<String>,
+    /// Value cached by BlueZ from the last read or notification
+    pub value: Option<Vec<u8>>,
+    pub descriptors: Vec<Descriptor>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Descriptor {
+    pub path: String,
+    pub uuid: String,
+    pub value: Option<Vec<u8>>,
+}
+
+impl ObjectTree {
+    /// Build the tree from one `GetManagedObjects` snapshot.
+    ///
+    /// Objects are attached to their parent through the `Adapter`, `Device`,
+    /// `Service` and `Characteristic` properties; orphans are dropped.
+    pub fn parse(managed_objects: &ManagedObjects) -> Result<Self> {
+        let mut objects: Vec<_> = managed_objects.iter().collect();
+        objects.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+
+        let mut adapters = Vec::new();
+        for (path, interfaces) in &objects {
+            if let Some(props) = interfaces.get("org.bluez.Adapter1") {
+                adapters.push(Adapter {
+                    path: path.to_string(),
+                    name: last_segment(path.as_str()).to_string(),
+                    address: address_prop(props)?,
+                    devices: Vec::new(),
+                });
+            }
+        }
+
+        let mut devices = Vec::new();
+        for (path, interfaces) in &objects {
+            if let Some(props) = interfaces.get("org.bluez.Device1") {
+                devices.push(Device::parse(path.as_str(), props)?);
+            }
+        }
+
+        let mut services = Vec::new();
+        let mut characteristics = Vec::new();
+        let mut descriptors = Vec::new();
+        for (path, interfaces) in &objects {
+            if let Some(props) = interfaces.get("org.bluez.GattService1") {
+                services.push((
+                    parent_prop(props, "Device", path.as_str())?,
+                    Service {
+                        path: path.to_string(),
+                        uuid: prop(props, "UUID")?.unwrap_or_default(),
+                        characteristics: Vec::new(),
+                    },
+                ));
+            }
+            if let Some(props) = interfaces.get("org.bluez.GattCharacteristic1") {
+                characteristics.push((
+                    parent_prop(props, "Service", path.as_str())?,
+                    Characteristic {
+                        path: path.to_string(),
+                        uuid: prop(props, "UUID")?.unwrap_or_default(),
+                        flags: prop(props, "Flags")?.unwrap_or_default(),
+                        value: prop(props, "Value")?,
+                        descriptors: Vec::new(),
+                    },
+                ));
+            }
+            if let Some(props) = interfaces.get("org.bluez.GattDescriptor1") {
+                descriptors.push((
+                    parent_prop(props, "Characteristic", path.as_str())?,
+                    Descriptor {
+                        path: path.to_string(),
+                        uuid: prop(props, "UUID")?.unwrap_or_default(),
+                        value: prop(props, "Value")?,
+                    },
+                ));
+            }
+        }
+
+        // Attach children bottom-up so every parent is complete when moved
+        let characteristics = attach(characteristics, descriptors, |c, d| c.descriptors.push(d));
+        let services = attach(services, characteristics, |s, c| s.characteristics.push(c));
+        let devices = attach(devices, services, |d, s| d.services.push(s));
+
+        let mut tree = ObjectTree { adapters };
+        for (adapter_path, device) in devices {
+            match tree.adapters.iter_mut().find(|a| a.path == adapter_path) {
+                Some(adapter) => adapter.devices.push(device),
+                None => tree.adapters.push(Adapter {
+                    name: last_segment(&adapter_path).to_string(),
+                    path: adapter_path,
+                    address: None,
+                    devices: vec![device],
+                }),
+            }
+        }
+
+        Ok(tree)
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = &Device> {
+        self.adapters.iter().flat_map(|a| a.devices.iter())
+    }
+
+    /// Find a device by address, optionally only on one adapter
+    pub fn find_device(&self, address: BdAddr, adapter: Option<&str>) -> Option<&Device> {
+        self.adapters
+            .iter()
+            .filter(|a| adapter.is_none_or(|name| name == a.name))
+            .flat_map(|a| a.devices.iter())
+            .find(|d| d.address == address)
+    }
+}
+
+impl Device {
+    /// Build a device without services from its `org.bluez.Device1`
+    /// properties, returning it with the path of its adapter
+    pub fn parse(path: &str, props: &Properties) -> Result<(String, Self)> {
+        let adapter = prop::<OwnedObjectPath>(props, "Adapter")?
+            .map(|p| p.to_string())
+            .unwrap_or_else(|| parent(path).to_string());
+        let device = Device {
+            path: path.to_string(),
+            adapter: last_segment(&adapter).to_string(),
+            address: address_prop(props)?
+                .or_else(|| BdAddr::from_object_path(path))
+                .unwrap_or_default(),
+            name: prop(props, "Name")?,
+            alias: prop(props, "Alias")?,
+            paired: prop(props, "Paired")?.unwrap_or(false),
+            bonded: prop(props, "Bonded")?.unwrap_or(false),
+            connected: prop(props, "Connected")?.unwrap_or(false),
+            trusted: prop(props, "Trusted")?.unwrap_or(false),
+            services_resolved: prop(props, "ServicesResolved")?.unwrap_or(false),
+            icon: prop(props, "Icon")?,
+            appearance: prop(props, "Appearance")?,
+            rssi: prop(props, "RSSI")?,
+            uuids: prop(props, "UUIDs")?.unwrap_or_default(),
+            services: Vec::new(),
+        };
+        Ok((adapter, device))
+    }
+
+    pub fn services_with_uuid<'a>(&'a self, uuid: &'a str) -> impl Iterator<Item = &'a Service> {
+        self.services.iter().filter(move |s| s.uuid == uuid)
+    }
+
+    pub fn has_battery_service(&self) -> bool {
+        self.services_with_uuid(BATTERY_UUID).next().is_some()
+    }
+}
+
+impl Service {
+    pub fn characteristic(&self, uuid: &str) -> Option<&Characteristic> {
+        self.characteristics.iter().find(|c| c.uuid == uuid)
+    }
+}
+
+impl Characteristic {
+    pub fn descriptor(&self, uuid: &str) -> Option<&Descriptor> {
+        self.descriptors.iter().find(|d| d.uuid == uuid)
+    }
+}
+
+/// Move each child into the parent whose path it names
+fn attach<P: HasPath, C>(
+    mut parents: Vec<(String, P)>,
+    children: Vec<(String, C)>,
+    mut push: impl FnMut(&mut P, C),
+) -> Vec<(String, P)> {
+    let index: HashMap<String, usize> = parents
+        .iter()
+        .enumerate()
+        .map(|(i, (_, p))| (p.path().to_string(), i))
+        .collect();
+
+    for (parent_path, child) in children {
+        if let Some(&i) = index.get(&parent_path) {
+            push(&mut parents[i].1, child);
+        }
+    }
+
+    parents
+}
+
+trait HasPath {
+    fn path(&self) -> &str;
+}
+
+impl HasPath for Device {
+    fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl HasPath for Service {
+    fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl HasPath for Characteristic {
+    fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+fn prop<T>(props: &Properties, name: &str) -> Result<Option<T>>
+where
+    T: TryFrom<OwnedValue, Error = zbus::zvariant::Error>,
+{
+    match props.get(name) {
+        Some(value) => Ok(Some(T::try_from(value.try_clone()?)?)),
+        None => Ok(None),
+    }
+}
+
+/// The `Address` property, if present and valid
+fn address_prop(props: &Properties) -> Result<Option<BdAddr>> {
+    Ok(prop::<String>(props, "Address")?.and_then(|address| address.parse().ok()))
+}
+
+/// Path of the parent object, from a property or else the object path
+fn parent_prop(props: &Properties, name: &str, path: &str) -> Result<String> {
+    Ok(prop::<OwnedObjectPath>(props, name)?
+        .map(|p| p.to_string())
+        .unwrap_or_else(|| parent(path).to_string()))
+}
+
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/')
+        .map(|(parent, _)| parent)
+        .unwrap_or_default()
+}
+
+fn last_segment(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BATTERY_LEVEL_UUID, BATTERY_USER_DESC, HID_UUID};
+    use zbus::zvariant::{ObjectPath, Value};
+
+    const HCI0: &str = "/org/bluez/hci0";
+    const HCI1: &str = "/org/bluez/hci1";
+    const KEYBOARD: &str = "/org/bluez/hci0/dev_D2_75_8A_E6_6A_FD";
+    const SERVICE: &str = "/org/bluez/hci0/dev_D2_75_8A_E6_6A_FD/service0010";
+    const CHAR: &str = "/org/bluez/hci0/dev_D2_75_8A_E6_6A_FD/service0010/char0011";
+    const DESC: &str = "/org/bluez/hci0/dev_D2_75_8A_E6_6A_FD/service0010/char0011/desc0013";
+    const MOUSE: &str = "/org/bluez/hci1/dev_11_22_33_44_55_66";
+
+    fn value<'a>(value: impl Into<Value<'a>>) -> OwnedValue {
+        value.into().try_to_owned().unwrap()
+    }
+
+    fn path(path: &str) -> OwnedValue {
+        value(ObjectPath::try_from(path).unwrap())
+    }
+
+    /// A hand-made snapshot: a keyboard with a Battery Service on hci0, a
+    /// mouse on hci1 without an `Adapter` property and a characteristic
+    /// whose service is gone
+    fn snapshot() -> ManagedObjects {
+        let objects = [
+            (
+                HCI0,
+                "org.bluez.Adapter1",
+                vec![("Address", value("00:1A:7D:DA:71:13"))],
+            ),
+            (
+                HCI1,
+                "org.bluez.Adapter1",
+                vec![("Address", value("00:1A:7D:DA:71:14"))],
+            ),
+            (
+                KEYBOARD,
+                "org.bluez.Device1",
+                vec![
+                    ("Adapter", path(HCI0)),
+                    ("Address", value("D2:75:8A:E6:6A:FD")),
+                    ("Name", value("Corne")),
+                    ("Alias", value("My Corne")),
+                    ("Paired", value(true)),
+                    ("Connected", value(true)),
+                    ("ServicesResolved", value(true)),
+                    ("Appearance", value(0x03c1u16)),
+                    ("UUIDs", value(vec![HID_UUID, BATTERY_UUID])),
+                ],
+            ),
+            (
+                SERVICE,
+                "org.bluez.GattService1",
+                vec![("Device", path(KEYBOARD)), ("UUID", value(BATTERY_UUID))],
+            ),
+            (
+                CHAR,
+                "org.bluez.GattCharacteristic1",
+                vec![
+                    ("Service", path(SERVICE)),
+                    ("UUID", value(BATTERY_LEVEL_UUID)),
+                    ("Flags", value(vec!["read", "notify"])),
+                    ("Value", value(vec![87u8])),
+                ],
+            ),
+            (
+                DESC,
+                "org.bluez.GattDescriptor1",
+                vec![
+                    ("Characteristic", path(CHAR)),
+                    ("UUID", value(BATTERY_USER_DESC)),
+                    ("Value", value(b"Peripheral 0".to_vec())),
+                ],
+            ),
+            (
+                "/org/bluez/hci0/dev_D2_75_8A_E6_6A_FD/service0020/char0021",
+                "org.bluez.GattCharacteristic1",
+                vec![
+                    (
+                        "Service",
+                        path("/org/bluez/hci0/dev_D2_75_8A_E6_6A_FD/service0020"),
+                    ),
+                    ("UUID", value(BATTERY_LEVEL_UUID)),
+                ],
+            ),
+            (
+                MOUSE,
+                "org.bluez.Device1",
+                vec![
+                    ("Address", value("11:22:33:44:55:66")),
+                    ("Name", value("Mouse")),
+                ],
+            ),
+        ];
+
+        objects
+            .into_iter()
+            .map(|(object, interface, props)| {
+                let props = props
+                    .into_iter()
+                    .map(|(name, value)| (name.to_string(), value))
+                    .collect();
+                (
+                    OwnedObjectPath::try_from(object).unwrap(),
+                    HashMap::from([(interface.to_string(), props)]),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn adapters() {
+        let tree = ObjectTree::parse(&snapshot()).unwrap();
+        let adapters: Vec<_> = tree
+            .adapters
+            .iter()
+            .map(|a| (a.name.as_str(), a.address.map(|a| a.to_string())))
+            .collect();
+        assert_eq!(
+            adapters,
+            [
+                ("hci0", Some("00:1A:7D:DA:71:13".to_string())),
+                ("hci1", Some("00:1A:7D:DA:71:14".to_string())),
+            ]
+        );
+        assert_eq!(tree.adapters[0].devices.len(), 1);
+        assert_eq!(tree.adapters[1].devices.len(), 1);
+    }
+
+    #[test]
+    fn device_with_battery_service() {
+        let tree = ObjectTree::parse(&snapshot()).unwrap();
+        let address = "D2:75:8A:E6:6A:FD".parse().unwrap();
+        let device = tree.find_device(address, Some("hci0")).unwrap();
+        assert_eq!(device.path, KEYBOARD);
+        assert_eq!(device.adapter, "hci0");
+        assert_eq!(device.name.as_deref(), Some("Corne"));
+        assert_eq!(device.alias.as_deref(), Some("My Corne"));
+        assert!(device.paired && device.connected && device.services_resolved);
+        assert!(!device.bonded && !device.trusted);
+        assert_eq!(device.appearance, Some(0x03c1));
+        assert_eq!(device.rssi, None);
+        assert_eq!(device.uuids, [HID_UUID, BATTERY_UUID]);
+        assert!(device.has_battery_service());
+        assert!(tree.find_device(address, Some("hci1")).is_none());
+    }
+
+    #[test]
+    fn characteristic_with_user_description() {
+        let tree = ObjectTree::parse(&snapshot()).unwrap();
+        let device = tree.devices().find(|d| d.path == KEYBOARD).unwrap();
+        let service = device.services_with_uuid(BATTERY_UUID).next().unwrap();
+        let level = service.characteristic(BATTERY_LEVEL_UUID).unwrap();
+        assert_eq!(level.path, CHAR);
+        assert_eq!(level.flags, ["read", "notify"]);
+        assert_eq!(level.value.as_deref(), Some(&[87u8][..]));
+
+        let description = level.descriptor(BATTERY_USER_DESC).unwrap();
+        assert_eq!(description.path, DESC);
+        assert_eq!(description.value.as_deref(), Some(&b"Peripheral 0"[..]));
+    }
+
+    #[test]
+    fn orphans_are_dropped() {
+        let tree = ObjectTree::parse(&snapshot()).unwrap();
+        let device = tree.devices().find(|d| d.path == KEYBOARD).unwrap();
+        // Only the service that exists, with only its own characteristic
+        assert_eq!(device.services.len(), 1);
+        assert_eq!(device.services[0].characteristics.len(), 1);
+        assert_eq!(tree.devices().count(), 2);
+    }
+
+    #[test]
+    fn adapter_falls_back_to_path_parent() {
+        let tree = ObjectTree::parse(&snapshot()).unwrap();
+        let mouse = &tree.adapters[1].devices[0];
+        assert_eq!(mouse.path, MOUSE);
+        assert_eq!(mouse.adapter, "hci1");
+        assert_eq!(mouse.address.to_string(), "11:22:33:44:55:66");
+        assert!(!mouse.has_battery_service());
+    }
+
+    #[test]
+    fn address_falls_back_to_object_path() {
+        let props = Properties::from([("Name".to_string(), value("Corne"))]);
+        let (adapter, device) = Device::parse(KEYBOARD, &props).unwrap();
+        assert_eq!(adapter, HCI0);
+        assert_eq!(device.address.to_string(), "D2:75:8A:E6:6A:FD");
+    }
+}
@@ -0,0 +1,254 @@
+use std::time::Duration;
+use zmk_battery_monitor::{
+    BatteryBackend, BdAddr, Config, DeviceEvent, FakeBackend, Monitor, MonitorEvent, ZmkError,
+};
+
+const KEYBOARD: &str = "AA:BB:CC:DD:EE:01";
+const OTHER: &str = "AA:BB:CC:DD:EE:02";
+
+fn addr(address: &str) -> BdAddr {
+    address.parse().unwrap()
+}
+
+fn config(toml: &str) -> Config {
+    let mut config: Config = toml::from_str(toml).unwrap();
+    config.history.enabled = false;
+    config
+}
+
+fn keyboard_config() -> Config {
+    config(&format!(
+        r#"
+        [[devices]]
+        name = "Corne"
+        address = "{KEYBOARD}"
+        "#
+    ))
+}
+
+async fn next(monitor: &mut Monitor) -> MonitorEvent {
+    tokio::time::timeout(Duration::from_secs(5), monitor.next())
+        .await
+        .expect("no monitor event")
+}
+
+/// Events up to and including the next `Refreshed`
+async fn until_refreshed(monitor: &mut Monitor) -> Vec<MonitorEvent> {
+    let mut events = Vec::new();
+    loop {
+        let event = next(monitor).await;
+        let done = matches!(event, MonitorEvent::Refreshed);
+        events.push(event);
+        if done {
+            return events;
+        }
+    }
+}
+
+#[tokio::test]
+async fn read_all_returns_scripted_levels_and_errors() {
+    let backend = FakeBackend::new()
+        .with_device("Corne", addr(KEYBOARD))
+        .with_device("Lily58", addr(OTHER));
+    backend.set_levels(addr(KEYBOARD), &[("Peripheral 1", 40), ("Central", 80)]);
+    let config = config(&format!(
+        r#"
+        [[devices]]
+        name = "Corne"
+        address = "{KEYBOARD}"
+        battery_names = {{ peripheral1 = "Right" }}
+
+        [[devices]]
+        name = "Lily58"
+        address = "{OTHER}"
+
+        [[devices]]
+        name = "Unpaired"
+        address = "AA:BB:CC:DD:EE:03"
+        "#
+    ));
+
+    let readings = backend.read_all(&config).await;
+    assert_eq!(readings.len(), 3);
+
+    let levels: Vec<_> = readings[0]
+        .batteries
+        .as_ref()
+        .unwrap()
+        .iter()
+        .map(|b| (b.name.as_str(), b.level))
+        .collect();
+    assert_eq!(levels, [("Central", 80), ("Right", 40)]);
+    assert!(matches!(
+        readings[1].batteries,
+        Err(ZmkError::NoBatteryService(_))
+    ));
+    assert!(matches!(readings[2].batteries, Err(ZmkError::NotPaired(_))));
+}
+
+#[tokio::test]
+async fn monitor_reports_initial_levels() {
+    let backend = FakeBackend::new().with_device("Corne", addr(KEYBOARD));
+    backend.set_levels(addr(KEYBOARD), &[("Central", 80), ("Peripheral 1", 75)]);
+
+    let mut monitor = Monitor::start(Box::new(backend.clone()), keyboard_config()).await;
+    let events = until_refreshed(&mut monitor).await;
+    let changed: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            MonitorEvent::LevelChanged {
+                battery, previous, ..
+            } => Some((battery.name.clone(), battery.level, *previous)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        changed,
+        [
+            ("Central".to_string(), 80, None),
+            ("Peripheral 1".to_string(), 75, None)
+        ]
+    );
+
+    let devices = monitor.devices();
+    let devices = devices.lock().unwrap();
+    assert!(devices[0].connected);
+    assert!(devices[0].error.is_none());
+    assert!(devices[0].last_updated.is_some());
+    assert!(devices[0].is_subscribed());
+}
+
+#[tokio::test]
+async fn set_error_fails_the_next_read() {
+    let backend = FakeBackend::new().with_device("Corne", addr(KEYBOARD));
+    backend.set_levels(addr(KEYBOARD), &[("Central", 80)]);
+    let mut monitor = Monitor::start(Box::new(backend.clone()), keyboard_config()).await;
+    until_refreshed(&mut monitor).await;
+
+    backend.set_error(addr(KEYBOARD), ZmkError::Timeout(Duration::from_secs(5)));
+    monitor.refresh().await;
+    until_refreshed(&mut monitor).await;
+
+    let devices = monitor.devices();
+    let devices = devices.lock().unwrap();
+    assert!(matches!(devices[0].error, Some(ZmkError::Timeout(_))));
+    assert!(devices[0].batteries.is_empty());
+    assert_eq!(devices[0].read_errors.get("timeout"), Some(&1));
+}
+
+#[tokio::test]
+async fn notify_level_is_pushed_to_the_monitor() {
+    let backend = FakeBackend::new().with_device("Corne", addr(KEYBOARD));
+    backend.set_levels(addr(KEYBOARD), &[("Central", 80)]);
+    let mut monitor = Monitor::start(Box::new(backend.clone()), keyboard_config()).await;
+    until_refreshed(&mut monitor).await;
+
+    backend.notify_level(addr(KEYBOARD), "Central", 79);
+    match next(&mut monitor).await {
+        MonitorEvent::LevelChanged {
+            device,
+            battery,
+            previous,
+        } => {
+            assert_eq!(device, 0);
+            assert_eq!((battery.name.as_str(), battery.level), ("Central", 79));
+            assert_eq!(previous, Some(80));
+        }
+        event => panic!("unexpected event {event:?}"),
+    }
+    assert_eq!(monitor.devices().lock().unwrap()[0].batteries[0].level, 79);
+}
+
+#[tokio::test]
+async fn disconnected_device_fails_without_auto_connect() {
+    let backend = FakeBackend::new().with_device("Corne", addr(KEYBOARD));
+    backend.set_levels(addr(KEYBOARD), &[("Central", 80)]);
+    backend.emit_event(DeviceEvent::Disconnected {
+        address: addr(KEYBOARD),
+    });
+
+    let mut monitor = Monitor::start(Box::new(backend.clone()), keyboard_config()).await;
+    until_refreshed(&mut monitor).await;
+
+    let devices = monitor.devices();
+    let devices = devices.lock().unwrap();
+    assert!(matches!(devices[0].error, Some(ZmkError::Disconnected(_))));
+    assert!(!devices[0].connected);
+    assert!(devices[0].batteries.is_empty());
+}
+
+#[tokio::test]
+async fn disconnected_device_is_connected_with_connect_timeout() {
+    let backend = FakeBackend::new().with_device("Corne", addr(KEYBOARD));
+    backend.set_levels(addr(KEYBOARD), &[("Central", 80)]);
+    backend.emit_event(DeviceEvent::Disconnected {
+        address: addr(KEYBOARD),
+    });
+    let config = config(&format!(
+        r#"
+        [[devices]]
+        name = "Corne"
+        address = "{KEYBOARD}"
+        auto_connect = true
+        "#
+    ));
+
+    let mut monitor = Monitor::start(Box::new(backend.clone()), config).await;
+    until_refreshed(&mut monitor).await;
+
+    let devices = monitor.devices();
+    let devices = devices.lock().unwrap();
+    assert!(devices[0].error.is_none());
+    assert!(devices[0].connected);
+    assert!(devices[0].reconnected);
+    assert_eq!(devices[0].batteries[0].level, 80);
+}
+
+#[tokio::test]
+async fn reconnect_event_triggers_a_refresh() {
+    let backend = FakeBackend::new().with_device("Corne", addr(KEYBOARD));
+    backend.set_levels(addr(KEYBOARD), &[("Central", 80)]);
+    let mut monitor = Monitor::start(Box::new(backend.clone()), keyboard_config()).await;
+    until_refreshed(&mut monitor).await;
+
+    backend.set_levels(addr(KEYBOARD), &[("Central", 70)]);
+    backend.emit_event(DeviceEvent::Connected {
+        address: addr(KEYBOARD),
+    });
+    let events = until_refreshed(&mut monitor).await;
+    assert!(matches!(
+        events[0],
+        MonitorEvent::Device {
+            device: 0,
+            event: DeviceEvent::Connected { .. }
+        }
+    ));
+    assert!(events.iter().any(|event| matches!(
+        event,
+        MonitorEvent::LevelChanged { battery, previous: Some(80), .. } if battery.level == 70
+    )));
+}
+
+#[tokio::test]
+async fn refresh_handle_refreshes_from_next() {
+    let backend = FakeBackend::new().with_device("Corne", addr(KEYBOARD));
+    backend.set_levels(addr(KEYBOARD), &[("Central", 80)]);
+    let mut monitor = Monitor::start(Box::new(backend.clone()), keyboard_config()).await;
+    until_refreshed(&mut monitor).await;
+
+    backend.set_levels(addr(KEYBOARD), &[("Central", 75)]);
+    let handle = monitor.refresh_handle();
+    let (events, refreshed) = tokio::join!(
+        until_refreshed(&mut monitor),
+        // Both are answered by the same read
+        async { tokio::join!(handle.refresh(), handle.refresh()) }
+    );
+    assert_eq!(refreshed, (true, true));
+    assert!(events.iter().any(|event| matches!(
+        event,
+        MonitorEvent::LevelChanged { battery, previous: Some(80), .. } if battery.level == 75
+    )));
+
+    drop(monitor);
+    assert!(!handle.refresh().await);
+}
@@ -0,0 +1,171 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use zmk_battery_monitor::format::{format_battery_info, format_device_state};
+use zmk_battery_monitor::{
+    BdAddr, Config, DeviceInformation, FakeBackend, History, Monitor, MonitorEvent, Sample,
+    ZmkError,
+};
+
+const KEYBOARD: &str = "AA:BB:CC:DD:EE:01";
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+fn addr() -> BdAddr {
+    KEYBOARD.parse().unwrap()
+}
+
+fn config() -> Config {
+    let mut config: Config = toml::from_str(&format!(
+        r#"
+        [[devices]]
+        name = "Corne"
+        address = "{KEYBOARD}"
+        "#
+    ))
+    .unwrap();
+    config.history.enabled = false;
+    config
+}
+
+/// Fresh history file for one test
+fn history_path(test: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "zmk-battery-monitor-{}-{test}.jsonl",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Record `levels` of the Central battery, one per hour, ending an hour ago
+fn seed(path: &Path, levels: &[u8]) -> History {
+    let mut history = History::at(path, None);
+    let now = SystemTime::now();
+    let samples: Vec<_> = levels
+        .iter()
+        .enumerate()
+        .map(|(i, &level)| Sample {
+            time: now - HOUR * (levels.len() - i) as u32,
+            device: "Corne".to_string(),
+            address: Some(addr()),
+            battery: "Central".to_string(),
+            level,
+        })
+        .collect();
+    history.record(&samples).unwrap();
+    history
+}
+
+/// Monitor over `backend` with the initial refresh done
+async fn start(backend: FakeBackend, history: Option<History>) -> Monitor {
+    let mut monitor = Monitor::start_with_history(Box::new(backend), config(), history).await;
+    while !matches!(monitor.next().await, MonitorEvent::Refreshed) {}
+    monitor
+}
+
+#[tokio::test]
+async fn batteries_with_tiers() {
+    let backend = FakeBackend::new().with_device("Corne", addr());
+    backend.set_levels(addr(), &[("Central", 80), ("Peripheral 1", 15)]);
+    let monitor = start(backend, None).await;
+
+    let devices = monitor.devices();
+    let devices = devices.lock().unwrap();
+    assert_eq!(
+        format_battery_info(&devices[0], &Default::default()),
+        "Central: 80%\nPeripheral 1: 15% ⚠ Low"
+    );
+}
+
+#[tokio::test]
+async fn no_battery_data() {
+    let backend = FakeBackend::new().with_device("Corne", addr());
+    backend.set_levels(addr(), &[]);
+    let monitor = start(backend, None).await;
+
+    let devices = monitor.devices();
+    let devices = devices.lock().unwrap();
+    assert_eq!(
+        format_device_state(&devices[0], &Default::default()),
+        "No battery data available"
+    );
+}
+
+#[tokio::test]
+async fn read_errors() {
+    let backend = FakeBackend::new().with_device("Corne", addr());
+    backend.set_error(addr(), ZmkError::Disconnected(addr()));
+    let mut monitor = start(backend.clone(), None).await;
+    {
+        let devices = monitor.devices();
+        let devices = devices.lock().unwrap();
+        assert_eq!(
+            format_device_state(&devices[0], &Default::default()),
+            "Asleep (disconnected)"
+        );
+    }
+
+    backend.set_error(addr(), ZmkError::NoBatteryService(addr()));
+    monitor.refresh().await;
+    let devices = monitor.devices();
+    let devices = devices.lock().unwrap();
+    assert_eq!(
+        format_device_state(&devices[0], &Default::default()),
+        format!("Error: Device {KEYBOARD} has no Battery Service")
+    );
+}
+
+#[tokio::test]
+async fn device_details_follow_the_levels() {
+    let backend = FakeBackend::new().with_device("Corne", addr());
+    backend.set_levels(addr(), &[("Central", 80)]);
+    backend.set_device_info(
+        addr(),
+        DeviceInformation {
+            manufacturer: Some("ZMK Project".to_string()),
+            ..Default::default()
+        },
+    );
+    let monitor = start(backend, None).await;
+
+    let devices = monitor.devices();
+    let devices = devices.lock().unwrap();
+    let text = format_device_state(&devices[0], &Default::default());
+    let details = devices[0].details.as_ref().unwrap().to_string();
+    assert_eq!(text, format!("Central: 80%\n{details}"));
+}
+
+#[tokio::test]
+async fn time_left_from_history() {
+    let path = history_path("time-left");
+    // One point per hour for ten hours
+    let history = seed(&path, &[90, 89, 88, 87, 86, 85, 84, 83, 82, 81]);
+    let backend = FakeBackend::new().with_device("Corne", addr());
+    backend.set_levels(addr(), &[("Central", 80)]);
+    let monitor = start(backend, Some(history)).await;
+
+    let devices = monitor.devices();
+    let devices = devices.lock().unwrap();
+    // 80% at 1% per hour
+    assert_eq!(
+        format_battery_info(&devices[0], &Default::default()),
+        "Central: 80% — ~3 days left"
+    );
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn charging_instead_of_time_left() {
+    let path = history_path("charging");
+    let history = seed(&path, &[50, 49, 48, 55, 60, 65]);
+    let backend = FakeBackend::new().with_device("Corne", addr());
+    backend.set_levels(addr(), &[("Central", 70)]);
+    let monitor = start(backend, Some(history)).await;
+
+    let devices = monitor.devices();
+    let devices = devices.lock().unwrap();
+    assert_eq!(
+        format_battery_info(&devices[0], &Default::default()),
+        "Central: 70% — charging"
+    );
+    std::fs::remove_file(path).unwrap();
+}